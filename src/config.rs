@@ -0,0 +1,279 @@
+use crate::generator::{CommuteInto, GroupSize, JoinGraph, RawMemo, Shape};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, Parser};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// All knobs of memo generation, given as command line arguments or in a TOML profile.
+#[derive(Args, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorConfig {
+    /// Number of groups
+    #[arg(long, short = 'g', default_value_t = 10)]
+    pub groups: usize,
+
+    /// Average number of expressions per group
+    #[arg(long, short = 'e', default_value_t = 1)]
+    pub exprs: usize,
+
+    /// Distribution of the number of expressions per group
+    #[arg(long, default_value = "uniform")]
+    pub group_size: GroupSize,
+
+    /// Number of extra fields in the payload of each expression
+    #[arg(long, default_value_t = 0)]
+    pub payload: usize,
+
+    /// Generate a DAG instead of a tree
+    #[arg(long, short = 'd')]
+    pub dag: bool,
+
+    /// Shape of generated trees
+    #[arg(long, default_value = "random")]
+    pub shape: Shape,
+
+    /// Keep growing trees until they are at least this deep
+    #[arg(long, default_value_t = 0)]
+    pub min_depth: usize,
+
+    /// Stop growing trees before they are deeper than this
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+
+    /// Relative weights of operators with 0, 1 and 2 children
+    #[arg(long, value_delimiter = ',', default_value = "10,30,30")]
+    pub arity_weights: Vec<usize>,
+
+    /// Proximity factor of referenced groups in a DAG (1 for no proximity preference)
+    #[arg(long, default_value_t = 4)]
+    pub proximity: usize,
+
+    /// Rate of replenishing groups to be referenced in a DAG, relative to the average arity
+    #[arg(long, default_value_t = 1.0)]
+    pub replenish: f64,
+
+    /// Number of roots, as for multiple queries
+    #[arg(long, default_value_t = 1)]
+    pub roots: usize,
+
+    /// Probability of referencing a group of a previous root
+    #[arg(long, default_value_t = 0.1)]
+    pub sharing: f64,
+
+    /// Number of blocks generated independently and in parallel, which changes the memo, unlike
+    /// the number of threads
+    #[arg(long, default_value_t = 1)]
+    pub blocks: usize,
+
+    /// Generate a fully explored join order memo for a join graph (ignores groups, expressions and DAG)
+    #[arg(long, short = 'j')]
+    pub join_graph: Option<JoinGraph>,
+
+    /// Number of relations in the join graph
+    #[arg(long, default_value_t = 4)]
+    pub relations: usize,
+
+    /// Generate physical expressions, enforcers and winners
+    #[arg(long, short = 'p')]
+    pub physical: bool,
+
+    /// Number of sort orders used as required physical properties
+    #[arg(long, default_value_t = 2)]
+    pub sort_orders: usize,
+
+    /// Number of expressions that make the memo cyclic
+    #[arg(long, default_value_t = 0)]
+    pub cycles: usize,
+
+    /// Number of expressions duplicated in other groups, which then need to be merged
+    #[arg(long, default_value_t = 0)]
+    pub duplicates: usize,
+
+    /// Number of joins copied with swapped children and the same payload
+    #[arg(long, default_value_t = 0)]
+    pub commute: usize,
+
+    /// Group where joins with swapped children are copied to
+    #[arg(long, default_value = "same")]
+    pub commute_into: CommuteInto,
+
+    /// Generate scalar predicate trees instead of unique constants
+    #[arg(long, short = 's')]
+    pub scalars: bool,
+
+    /// Maximum depth of AND/OR in scalar predicates
+    #[arg(long, default_value_t = 2)]
+    pub scalar_depth: usize,
+
+    /// Probability of reusing an existing predicate subtree
+    #[arg(long, default_value_t = 0.2)]
+    pub scalar_sharing: f64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        // the same defaults as the command line
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            config: GeneratorConfig,
+        }
+        Defaults::parse_from(["memobench"]).config
+    }
+}
+
+impl GeneratorConfig {
+    /// Load a TOML profile, overridden by arguments explicitly given in the command line.
+    pub fn with_profile(self, path: &str, matches: &ArgMatches) -> Result<Self, Box<dyn Error>> {
+        let mut profile: toml::Table = toml::from_str(&std::fs::read_to_string(path)?)?;
+        for (key, value) in toml::Table::try_from(&self)? {
+            if matches.value_source(&key) == Some(ValueSource::CommandLine) {
+                profile.insert(key, value);
+            }
+        }
+        Ok(profile.try_into()?)
+    }
+
+    /// Effective configuration on a single line, with the same keys as a profile.
+    pub fn summary(&self) -> String {
+        match toml::to_string(self) {
+            Ok(s) => s.lines().collect::<Vec<_>>().join(", "),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.arity_weights.is_empty() || self.arity_weights.len() > 3 || self.arity_weights.iter().sum::<usize>() == 0 {
+            return Err("arity weights must be 1 to 3 values, not all zero".into());
+        }
+        if self.exprs == 0 {
+            return Err("expressions per group must be at least 1".into());
+        }
+        if self.proximity == 0 {
+            return Err("proximity must be at least 1".into());
+        }
+        if let Some(m) = self.max_depth {
+            if m < 2 || m < self.min_depth {
+                return Err("max depth must be at least 2 and at least the min depth".into());
+            }
+        }
+        if self.join_graph.is_some() && !(1..64).contains(&self.relations) {
+            return Err("relations must be between 1 and 63".into());
+        }
+        if self.roots == 0 {
+            return Err("roots must be at least 1".into());
+        }
+        if self.blocks == 0 || self.blocks > self.groups || (self.roots > 1 && self.roots < self.blocks) {
+            return Err("blocks must be at least 1, at most the groups and, with multiple roots, at most the roots".into());
+        }
+        if !(0.0..=1.0).contains(&self.sharing) || !(0.0..=1.0).contains(&self.scalar_sharing) {
+            return Err("sharing probabilities must be between 0 and 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Builds a memo and runs all configured passes over it, each with a seed derived from the
+/// same seed, so that a run can be repeated.
+pub struct RawMemoBuilder {
+    config: GeneratorConfig,
+    threads: usize, // for generating blocks, 0 for all cores
+}
+
+impl From<GeneratorConfig> for RawMemoBuilder {
+    fn from(config: GeneratorConfig) -> Self {
+        RawMemoBuilder { config, threads: 0 }
+    }
+}
+
+// setters, for using the generator from other code
+#[allow(dead_code)]
+impl RawMemoBuilder {
+    pub fn new() -> Self {
+        RawMemoBuilder { config: GeneratorConfig::default(), threads: 0 }
+    }
+
+    pub fn groups(mut self, groups: usize) -> Self { self.config.groups = groups; self }
+    pub fn exprs(mut self, exprs: usize) -> Self { self.config.exprs = exprs; self }
+    pub fn group_size(mut self, group_size: GroupSize) -> Self { self.config.group_size = group_size; self }
+    pub fn payload(mut self, payload: usize) -> Self { self.config.payload = payload; self }
+    pub fn dag(mut self, dag: bool) -> Self { self.config.dag = dag; self }
+    pub fn shape(mut self, shape: Shape) -> Self { self.config.shape = shape; self }
+    pub fn min_depth(mut self, min_depth: usize) -> Self { self.config.min_depth = min_depth; self }
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self { self.config.max_depth = max_depth; self }
+    pub fn arity_weights(mut self, arity_weights: Vec<usize>) -> Self { self.config.arity_weights = arity_weights; self }
+    pub fn proximity(mut self, proximity: usize) -> Self { self.config.proximity = proximity; self }
+    pub fn replenish(mut self, replenish: f64) -> Self { self.config.replenish = replenish; self }
+    pub fn blocks(mut self, blocks: usize) -> Self { self.config.blocks = blocks; self }
+    pub fn roots(mut self, roots: usize) -> Self { self.config.roots = roots; self }
+    pub fn sharing(mut self, sharing: f64) -> Self { self.config.sharing = sharing; self }
+    pub fn join_graph(mut self, join_graph: Option<JoinGraph>) -> Self { self.config.join_graph = join_graph; self }
+    pub fn relations(mut self, relations: usize) -> Self { self.config.relations = relations; self }
+    pub fn physical(mut self, physical: bool) -> Self { self.config.physical = physical; self }
+    pub fn sort_orders(mut self, sort_orders: usize) -> Self { self.config.sort_orders = sort_orders; self }
+    pub fn cycles(mut self, cycles: usize) -> Self { self.config.cycles = cycles; self }
+    pub fn duplicates(mut self, duplicates: usize) -> Self { self.config.duplicates = duplicates; self }
+    pub fn commute(mut self, commute: usize) -> Self { self.config.commute = commute; self }
+    pub fn commute_into(mut self, commute_into: CommuteInto) -> Self { self.config.commute_into = commute_into; self }
+    pub fn scalars(mut self, scalars: bool) -> Self { self.config.scalars = scalars; self }
+    pub fn scalar_depth(mut self, scalar_depth: usize) -> Self { self.config.scalar_depth = scalar_depth; self }
+    pub fn scalar_sharing(mut self, scalar_sharing: f64) -> Self { self.config.scalar_sharing = scalar_sharing; self }
+}
+
+impl RawMemoBuilder {
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Generate the initial memo, as a join graph or randomly, and run all passes.
+    pub fn build(self, seed: u64) -> Result<RawMemo, Box<dyn Error>> {
+        self.config.validate()?;
+        let memo = match &self.config.join_graph {
+            Some(graph) => RawMemo::join_graph(graph.clone(), self.config.relations, ChaCha8Rng::seed_from_u64(seed)),
+            None if self.config.blocks > 1 => {
+                let threads = match self.threads {
+                    0 => std::thread::available_parallelism()?.get(),
+                    n => n,
+                };
+                RawMemo::new_blocks(&self.config, seed, threads)
+            }
+            None => RawMemo::new(&self.config, ChaCha8Rng::seed_from_u64(seed)),
+        };
+        self.build_from(memo, seed)
+    }
+
+    /// Run all passes over a given initial memo, e.g., one built from SQL.
+    pub fn build_from(self, mut memo: RawMemo, seed: u64) -> Result<RawMemo, Box<dyn Error>> {
+        self.config.validate()?;
+        let config = &self.config;
+
+        if config.cycles > 0 {
+            memo.inject_cycles(config.cycles, ChaCha8Rng::seed_from_u64(seed + 4000));
+        }
+
+        if config.scalars {
+            memo.add_scalars(config.scalar_depth, config.scalar_sharing, ChaCha8Rng::seed_from_u64(seed + 3000));
+        }
+
+        if config.payload > 0 {
+            memo.add_payload(config.payload, ChaCha8Rng::seed_from_u64(seed + 7000));
+        }
+
+        if config.duplicates > 0 {
+            memo.inject_duplicates(config.duplicates, ChaCha8Rng::seed_from_u64(seed + 5000));
+        }
+
+        if config.commute > 0 {
+            memo.commute(config.commute, config.commute_into.clone(), ChaCha8Rng::seed_from_u64(seed + 6000));
+        }
+
+        if config.physical {
+            memo.add_physical(config.sort_orders, ChaCha8Rng::seed_from_u64(seed + 2000));
+        }
+
+        Ok(memo)
+    }
+}
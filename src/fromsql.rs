@@ -0,0 +1,265 @@
+use crate::generator::{RawExpr, RawGroup, RawMemo, OP_AGGREGATE, OP_FILTER, OP_JOIN, OP_PROJECT, OP_SCAN};
+use log::info;
+use sqlparser::ast::{Expr, GroupByExpr, Query, Select, SelectItem, SetExpr, Statement, TableFactor, Visit, Visitor};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+const TPCH: [&str; 22] = [
+    include_str!("../queries/tpch/q01.sql"),
+    include_str!("../queries/tpch/q02.sql"),
+    include_str!("../queries/tpch/q03.sql"),
+    include_str!("../queries/tpch/q04.sql"),
+    include_str!("../queries/tpch/q05.sql"),
+    include_str!("../queries/tpch/q06.sql"),
+    include_str!("../queries/tpch/q07.sql"),
+    include_str!("../queries/tpch/q08.sql"),
+    include_str!("../queries/tpch/q09.sql"),
+    include_str!("../queries/tpch/q10.sql"),
+    include_str!("../queries/tpch/q11.sql"),
+    include_str!("../queries/tpch/q12.sql"),
+    include_str!("../queries/tpch/q13.sql"),
+    include_str!("../queries/tpch/q14.sql"),
+    include_str!("../queries/tpch/q15.sql"),
+    include_str!("../queries/tpch/q16.sql"),
+    include_str!("../queries/tpch/q17.sql"),
+    include_str!("../queries/tpch/q18.sql"),
+    include_str!("../queries/tpch/q19.sql"),
+    include_str!("../queries/tpch/q20.sql"),
+    include_str!("../queries/tpch/q21.sql"),
+    include_str!("../queries/tpch/q22.sql"),
+];
+
+impl RawMemo {
+    /// Build the initial logical tree of queries given as `tpch:<n>` for one of the
+    /// built-in TPC-H queries, `tpch` for all of them, or as the path of a file with
+    /// SQL statements. Each query is a root. Subqueries are joined with their outer
+    /// query, as an apply would be, and scans are shared between different queries.
+    pub fn from_sql(spec: &str) -> Result<Self, Box<dyn Error>> {
+        let sql = match spec.strip_prefix("tpch") {
+            Some("") => TPCH.join("\n"),
+            Some(n) => match n.strip_prefix(":").and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n >= 1 && n <= TPCH.len() => TPCH[n - 1].to_string(),
+                _ => return Err(format!("no such TPC-H query: {}", spec).into()),
+            },
+            None => std::fs::read_to_string(spec)?,
+        };
+
+        info!("target: {}", spec);
+
+        let start = Instant::now();
+
+        let mut builder = SqlBuilder {
+            memo: RawMemo {
+                exprs: Vec::new(),
+                groups: Vec::new(),
+                entries: Vec::new(),
+                phys: Vec::new(),
+                scalars: Vec::new(),
+                cycles: Vec::new(),
+                merges: Vec::new(),
+                commuted: Vec::new(),
+            },
+            views: HashMap::new(),
+            scans: HashMap::new(),
+            used: HashSet::new(),
+        };
+
+        for statement in Parser::parse_sql(&GenericDialect {}, &sql)?.iter() {
+            builder.used.clear();
+            match statement {
+                Statement::Query(query) => {
+                    let root = builder.query(query)?;
+                    builder.memo.entries.push(root);
+                }
+                Statement::CreateView { name, query, .. } => {
+                    let view = builder.query(query)?;
+                    builder.views.insert(name.to_string(), view);
+                }
+                Statement::Drop { .. } => {}
+                other => return Err(format!("unsupported statement: {}", other).into()),
+            }
+        }
+
+        let memo = builder.memo;
+        if memo.entries.is_empty() {
+            return Err("no query found".into());
+        }
+
+        info!(
+            "result: {} groups, {} expressions, {} roots, depth {} ({:?})",
+            memo.groups.len(),
+            memo.exprs.len(),
+            memo.entries.len(),
+            memo.depth(),
+            start.elapsed(),
+        );
+
+        Ok(memo)
+    }
+}
+
+struct SqlBuilder {
+    memo: RawMemo,
+    views: HashMap<String, usize>, // views and common table expressions
+    scans: HashMap<String, usize>, // last scan of each table
+    used: HashSet<usize>, // scans used by the current statement
+}
+
+impl SqlBuilder {
+    fn add(&mut self, op: usize, children: Vec<usize>) -> usize {
+        let group_id = self.memo.groups.len();
+        self.memo.groups.push(RawGroup { exprs: vec![self.memo.exprs.len()], id: group_id, ..Default::default() });
+        self.memo.exprs.push(RawExpr { op, children, pred: None, data: self.memo.exprs.len(), extra: vec![] });
+        group_id
+    }
+
+    fn query(&mut self, query: &Query) -> Result<usize, Box<dyn Error>> {
+        if let Some(with) = &query.with {
+            for cte in with.cte_tables.iter() {
+                let view = self.query(&cte.query)?;
+                self.views.insert(cte.alias.name.to_string(), view);
+            }
+        }
+
+        // ORDER BY and LIMIT are physical concerns, ignored
+        match query.body.as_ref() {
+            SetExpr::Select(select) => self.select(select),
+            SetExpr::Query(query) => self.query(query),
+            other => Err(format!("unsupported query: {}", other).into()),
+        }
+    }
+
+    fn select(&mut self, select: &Select) -> Result<usize, Box<dyn Error>> {
+        let mut input = None;
+        for table in select.from.iter() {
+            let mut tree = self.table(&table.relation)?;
+            for join in table.joins.iter() {
+                let right = self.table(&join.relation)?;
+                tree = self.add(OP_JOIN, vec![tree, right]);
+            }
+            input = Some(match input {
+                None => tree,
+                Some(left) => self.add(OP_JOIN, vec![left, tree]),
+            });
+        }
+        let mut input = input.ok_or("query without FROM")?;
+
+        if let Some(selection) = &select.selection {
+            input = self.subqueries(input, selection)?;
+            input = self.add(OP_FILTER, vec![input]);
+        }
+
+        let grouped = match &select.group_by {
+            GroupByExpr::All(_) => true,
+            GroupByExpr::Expressions(exprs, _) => !exprs.is_empty(),
+        };
+        let mut aggregates = OuterVisitor::default();
+        for item in select.projection.iter() {
+            let _ = item.visit(&mut aggregates);
+        }
+        if grouped || aggregates.aggregates > 0 {
+            input = self.add(OP_AGGREGATE, vec![input]);
+        }
+
+        if let Some(having) = &select.having {
+            input = self.subqueries(input, having)?;
+            input = self.add(OP_FILTER, vec![input]);
+        }
+
+        for item in select.projection.iter() {
+            if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = item {
+                input = self.subqueries(input, expr)?;
+            }
+        }
+        Ok(self.add(OP_PROJECT, vec![input]))
+    }
+
+    fn table(&mut self, table: &TableFactor) -> Result<usize, Box<dyn Error>> {
+        match table {
+            TableFactor::Table { name, .. } => {
+                let name = name.to_string();
+                if let Some(view) = self.views.get(&name) {
+                    return Ok(*view);
+                }
+                match self.scans.get(&name) {
+                    // shared with a previous query, unless it is a self-join
+                    Some(scan) if !self.used.contains(scan) => {
+                        self.used.insert(*scan);
+                        Ok(*scan)
+                    }
+                    _ => {
+                        let scan = self.add(OP_SCAN, vec![]);
+                        self.scans.insert(name, scan);
+                        self.used.insert(scan);
+                        Ok(scan)
+                    }
+                }
+            }
+            TableFactor::Derived { subquery, .. } => self.query(subquery),
+            TableFactor::NestedJoin { table_with_joins, .. } => {
+                let mut tree = self.table(&table_with_joins.relation)?;
+                for join in table_with_joins.joins.iter() {
+                    let right = self.table(&join.relation)?;
+                    tree = self.add(OP_JOIN, vec![tree, right]);
+                }
+                Ok(tree)
+            }
+            other => Err(format!("unsupported table: {}", other).into()),
+        }
+    }
+
+    /// Join each subquery in the expression (but not those nested in them) with the input.
+    fn subqueries(&mut self, mut input: usize, expr: &Expr) -> Result<usize, Box<dyn Error>> {
+        let mut visitor = OuterVisitor::default();
+        let _ = expr.visit(&mut visitor);
+        for query in visitor.subqueries.iter() {
+            let subquery = self.query(query)?;
+            input = self.add(OP_JOIN, vec![input, subquery]);
+        }
+        Ok(input)
+    }
+}
+
+/// Collects subqueries and aggregate calls that are not nested in some other subquery.
+#[derive(Default)]
+struct OuterVisitor {
+    depth: usize,
+    subqueries: Vec<Query>,
+    aggregates: usize,
+}
+
+impl Visitor for OuterVisitor {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if self.depth == 0 {
+            match expr {
+                Expr::Subquery(query)
+                | Expr::Exists { subquery: query, .. }
+                | Expr::InSubquery { subquery: query, .. } => self.subqueries.push(query.as_ref().clone()),
+                Expr::Function(f) => {
+                    let name = f.name.to_string().to_lowercase();
+                    if ["sum", "avg", "count", "min", "max"].contains(&name.as_str()) {
+                        self.aggregates += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
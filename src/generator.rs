@@ -1,128 +1,594 @@
+use crate::config::GeneratorConfig;
+use clap::ValueEnum;
 use log::info;
 use rand::distributions::WeightedIndex;
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 use std::cmp::max;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::time::Instant;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct RawExpr {
     pub op: usize,
     pub children: Vec<usize>,
+    pub pred: Option<usize>, // root of scalar predicate, if generated
+    pub data: usize, // payload, unique unless duplicated
+    pub extra: Vec<u64>, // additional payload fields
+}
+
+impl RawExpr {
+    /// Children in a canonical order, for commutative operators.
+    pub fn canonical_children(&self) -> Vec<usize> {
+        let mut children = self.children.clone();
+        if self.op == OP_JOIN {
+            children.sort();
+        }
+        children
+    }
 }
 
+// logical operators
+pub const OP_SCAN: usize = 0;
+pub const OP_FILTER: usize = 1;
+pub const OP_JOIN: usize = 2;
+pub const OP_PROJECT: usize = 3;
+pub const OP_AGGREGATE: usize = 4;
+
+// scalar operators
+pub const SCALAR_CONST: usize = 0;
+pub const SCALAR_COLUMN: usize = 1;
+pub const SCALAR_EQ: usize = 2;
+pub const SCALAR_LT: usize = 3;
+pub const SCALAR_AND: usize = 4;
+pub const SCALAR_OR: usize = 5;
+
 #[derive(Clone)]
+pub struct RawScalar {
+    pub op: usize,
+    pub children: Vec<usize>,
+    pub value: u64, // constant or column index
+}
+
+// physical operators
+pub const PHYS_TABLE_SCAN: usize = 0;
+pub const PHYS_FILTER: usize = 1;
+pub const PHYS_HASH_JOIN: usize = 2;
+pub const PHYS_MERGE_JOIN: usize = 3;
+pub const PHYS_NESTED_LOOP: usize = 4;
+pub const PHYS_SORT: usize = 5;
+pub const PHYS_PROJECT: usize = 6;
+pub const PHYS_HASH_AGGREGATE: usize = 7;
+
+#[derive(Clone)]
+pub struct RawPhysExpr {
+    pub op: usize,
+    pub expr: Option<usize>, // implemented logical expression, None for enforcers
+    pub children: Vec<(usize, usize)>, // (group, required property)
+    pub prop: usize, // delivered property (0 for none, k for sorted on key k)
+    pub cost: u64, // including the cost of inputs
+}
+
+#[derive(Clone, Default)]
 pub struct RawGroup {
     pub exprs: Vec<usize>,
     pub id: usize,
+    pub phys: Vec<usize>,
+    pub winners: BTreeMap<usize, usize>, // required property -> best physical expression
 }
 
-#[derive(Clone)]
-pub struct RawMemo {
-    pub exprs: Vec<RawExpr>,
-    pub groups: Vec<RawGroup>,
-    pub entry: usize,
-}
+#[derive(Clone, ValueEnum, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JoinGraph { Chain, Star, Cycle, Clique, Random }
 
-impl RawMemo {
-    pub fn new(ngroups: usize, nexprs: usize, dag: bool, mut rng: ChaCha8Rng) -> Self {
-        // FIXME: MAGIC NUMBERS
-        let weights = [10, 30, 30]; // distribution operator arity
-        let proximity = 4; // proximity factor (1 for no proximity preference)
+#[derive(Clone, ValueEnum, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommuteInto { Same, Other }
 
-        info!("target: {} groups, {} expressions/group", ngroups, nexprs);
+#[derive(Clone, ValueEnum, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Shape { Random, LeftDeep, RightDeep, Bushy, ZigZag }
 
-        let start = Instant::now();
+#[derive(Clone, ValueEnum, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupSize { Uniform, Geometric, Zipf, Fixed, Bimodal }
+
+/// Samples the number of expressions in each group, for a given average.
+struct GroupSizes {
+    dist: GroupSize,
+    nexprs: usize,
+    zipf: Option<WeightedIndex<f64>>, // ranks 1..=n, with n such that the mean is nexprs
+}
 
-        let mut memo = RawMemo {
-            exprs: Vec::new(),
-            groups: Vec::new(),
-            entry: 0,
+/// Position of the deepest pending group, the most recent one if tied.
+fn deepest(gqueue: &[(usize, usize)]) -> usize {
+    (0..gqueue.len()).max_by_key(|i| gqueue[*i].1).unwrap()
+}
+
+/// Position of the shallowest pending group, the oldest one if tied.
+fn shallowest(gqueue: &[(usize, usize)]) -> usize {
+    (0..gqueue.len()).min_by_key(|i| gqueue[*i].1).unwrap()
+}
+
+impl GroupSizes {
+    fn new(dist: GroupSize, nexprs: usize) -> Self {
+        let zipf = match dist {
+            GroupSize::Zipf => {
+                let mut weights = vec![1.0];
+                let mut harmonic = 1.0;
+                while (weights.len() as f64) < nexprs as f64 * harmonic {
+                    let k = weights.len() as f64 + 1.0;
+                    weights.push(1.0 / k);
+                    harmonic += 1.0 / k;
+                }
+                Some(WeightedIndex::new(&weights).unwrap())
+            }
+            _ => None,
         };
+        GroupSizes { dist, nexprs, zipf }
+    }
+
+    fn sample(&self, rng: &mut ChaCha8Rng) -> usize {
+        match self.dist {
+            GroupSize::Uniform => rng.gen_range(0..self.nexprs * 2),
+            GroupSize::Fixed => self.nexprs,
+            GroupSize::Geometric => match self.nexprs {
+                0 | 1 => self.nexprs,
+                n => {
+                    let u: f64 = 1.0 - rng.gen::<f64>();
+                    (u.ln() / (1.0 - 1.0 / n as f64).ln()).ceil().max(1.0) as usize
+                }
+            },
+            GroupSize::Zipf => self.zipf.as_ref().unwrap().sample(rng) + 1,
+            GroupSize::Bimodal => {
+                // mostly single expression groups, with a few large ones
+                if self.nexprs > 1 && rng.gen_bool(0.2) { 5 * self.nexprs - 4 } else { self.nexprs.min(1) }
+            }
+        }
+    }
+}
 
+/// A generated group with its expressions, as streamed in insertion order.
+pub struct StreamGroup {
+    pub id: usize,
+    pub exprs: Vec<(usize, RawExpr)>, // expression ids and expressions
+}
+
+/// Generates the initial memo one group at a time, keeping only groups pending to be
+/// referenced, so that memos larger than memory can be streamed into a backend. The same
+/// seed always yields the same groups, also when collected by `RawMemo::new`.
+pub struct Generator {
+    config: GeneratorConfig,
+    rng: ChaCha8Rng,
+    dist: WeightedIndex<usize>, // operator arity
+    sizes: GroupSizes,
+    avg_arity: f64,
+    linear: bool,
+    bounded: bool,
+    root: usize,
+    first: usize, // first group of the current root
+    gqueue: Vec<(usize, usize)>, // pending groups and their depth
+    ngroups: usize,
+    nexprs: usize,
+    entries: Vec<usize>,
+    depth: usize, // deepest root so far
+    ready: VecDeque<StreamGroup>,
+}
+
+impl Generator {
+    pub fn new(config: &GeneratorConfig, rng: ChaCha8Rng) -> Self {
         let mut tot = 0;
         let mut cnt = 0;
-        for (i, v) in weights.iter().enumerate() {
+        for (i, v) in config.arity_weights.iter().enumerate() {
             tot += i * v;
             cnt += v;
         }
-        let dist = WeightedIndex::new(&weights).unwrap();
-
-        // Generate groups
-        let mut gqueue: Vec<usize> = vec![];
-        while memo.groups.len() < ngroups || gqueue.len() > 1 {
-            let mut exprs: Vec<usize> = vec![];
-
-            let ngen = rng.gen_range(0..nexprs * 2);
-
-            // Generate expressions even if no groups to reference (will be a scan!)
-            while exprs.len() < ngen {
-                let arity = dist.sample(&mut rng);
-                let mut children: Vec<usize> = vec![];
-                let mut cset: HashSet<usize> = HashSet::new();
-                for i in 0..arity {
-                    if gqueue.len() > 0 {
-                        let idx = rng.gen_range(0..gqueue.len());
-                        // avoid using the same group twice
-                        // as an operand to the same expression
+        let shape = &config.shape;
+        Generator {
+            config: config.clone(),
+            rng,
+            dist: WeightedIndex::new(&config.arity_weights).unwrap(),
+            sizes: GroupSizes::new(config.group_size.clone(), config.exprs),
+            avg_arity: ((config.exprs * tot / cnt) as f64 * config.replenish),
+            linear: [Shape::LeftDeep, Shape::RightDeep, Shape::ZigZag].contains(shape),
+            bounded: config.min_depth > 0 || config.max_depth.is_some(),
+            root: 0,
+            first: 0,
+            gqueue: vec![],
+            ngroups: 0,
+            nexprs: 0,
+            entries: vec![],
+            depth: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Root groups generated so far.
+    pub fn entries(&self) -> &Vec<usize> {
+        &self.entries
+    }
+
+    /// Number of groups and expressions generated so far.
+    pub fn len(&self) -> (usize, usize) {
+        (self.ngroups, self.nexprs)
+    }
+
+    /// Depth of the deepest root so far. Groups that are referenced again in a DAG or shared
+    /// with previous roots count as scans, so that their depth need not be kept.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Generate the next group of the current root, possibly with extra scan groups before
+    /// it, or finish the root.
+    fn step(&mut self) {
+        let config = &self.config;
+        let (ngroups, dag, sharing) = (config.groups, config.dag, config.sharing);
+        let (shape, min_depth, max_depth) = (&config.shape, config.min_depth, config.max_depth);
+        let proximity = config.proximity; // proximity factor (1 for no proximity preference)
+        let (first, linear, bounded) = (self.first, self.linear, self.bounded);
+        let target = ngroups * (self.root + 1) / config.roots;
+        let rng = &mut self.rng;
+        let gqueue = &mut self.gqueue;
+
+        // lower bound for the depth of the tree once pending groups are combined
+        let estimate = match (bounded, gqueue.iter().map(|g| g.1).max()) {
+            (true, Some(d)) if linear => d + gqueue.len() - 1,
+            (true, Some(d)) => d + gqueue.len().next_power_of_two().trailing_zeros() as usize,
+            _ => 0,
+        };
+        let growing = (self.ngroups < target || estimate < min_depth)
+            && max_depth.is_none_or(|m| estimate < m);
+        if !growing && gqueue.len() == 1 {
+            let (entry, depth) = gqueue.pop().unwrap();
+            self.entries.push(entry);
+            self.depth = max(self.depth, depth);
+            self.root += 1;
+            self.first = self.ngroups;
+            return;
+        }
+        // once bounded trees stop growing, only join pending groups
+        let balance = !growing && max_depth.is_some();
+        // past the target, trees that are not deep enough grow over the deepest subtree
+        let deepen = growing && self.ngroups >= target;
+
+        let mut exprs: Vec<(usize, RawExpr)> = vec![];
+        let mut depth = 1;
+
+        let ngen = self.sizes.sample(rng);
+
+        // Generate expressions even if no groups to reference (will be a scan!)
+        while exprs.len() < ngen {
+            let arity = if balance { 2 } else { self.dist.sample(rng) };
+            let mut children: Vec<(usize, usize)> = vec![];
+            for i in 0..arity {
+                // the first child of linear shapes is the spine, i.e., the deepest pending group
+                let spine = (linear || deepen) && i == 0;
+                if !spine && first > 0 && sharing > 0.0 && rng.gen_bool(sharing) {
+                    // share a group with previous roots
+                    let c = rng.gen_range(0..first);
+                    if !children.iter().any(|g| g.0 == c) {
+                        children.push((c, 1));
+                    }
+                } else if gqueue.len() > 0 {
+                    let idx = match shape {
+                        _ if spine => Some(deepest(gqueue)),
+                        Shape::Random if !balance => Some(rng.gen_range(0..gqueue.len())),
+                        // bushy trees join subtrees of the same depth while growing
+                        Shape::Bushy if growing && !deepen && i > 0 => {
+                            gqueue.iter().position(|g| g.1 == children[0].1)
+                        }
+                        _ => Some(shallowest(gqueue)),
+                    };
+                    // avoid using the same group twice
+                    // as an operand to the same expression
+                    if let Some(idx) = idx {
                         let c = gqueue[idx];
-                        if !cset.contains(&c) {
-                            cset.insert(c);
-                            children.push(gqueue[idx]);
+                        if !children.iter().any(|g| g.0 == c.0) {
+                            children.push(c);
                             gqueue.remove(idx);
                         }
                     }
-                    if children.len() <= i {
-                        // failed to find a group, make one extra 'scan' node now
-                        children.push(memo.groups.len());
-                        memo.groups.push(RawGroup {
-                            exprs: vec![memo.exprs.len()],
-                            id: memo.groups.len(),
-                        });
-                        memo.exprs.push(RawExpr {
-                            op: 0,
+                }
+                if children.len() <= i {
+                    // failed to find a group, make one extra 'scan' node now
+                    children.push((self.ngroups, 1));
+                    self.ready.push_back(StreamGroup {
+                        id: self.ngroups,
+                        exprs: vec![(self.nexprs, RawExpr {
+                            op: OP_SCAN,
                             children: vec![],
-                        });
-                    }
+                            pred: None,
+                            data: self.nexprs,
+                            extra: vec![],
+                        })],
+                    });
+                    self.ngroups += 1;
+                    self.nexprs += 1;
                 }
-                let expr_id = memo.exprs.len();
-                exprs.push(expr_id);
+            }
+            if children.len() > 1 && (*shape == Shape::RightDeep || (*shape == Shape::ZigZag && children[0].1 % 2 == 1)) {
+                children.rotate_left(1);
+            }
+            depth = max(depth, 1 + children.iter().map(|c| c.1).max().unwrap_or(0));
+
+            let expr_id = self.nexprs;
+            self.nexprs += 1;
 
-                let op = children.len(); // FIXME: more ops?
-                memo.exprs.push(RawExpr { op, children });
+            let op = children.len(); // FIXME: more ops?
+            let children = children.into_iter().map(|c| c.0).collect();
+            exprs.push((expr_id, RawExpr { op, children, pred: None, data: expr_id, extra: vec![] }));
+        }
+
+        if ngen > 0 {
+            let group_id = self.ngroups;
+            self.ngroups += 1;
+            self.ready.push_back(StreamGroup { id: group_id, exprs });
+
+            // While we don't have enough groups, collect operands for future expressions
+            if group_id < target && dag {
+                // replenish groups to be referenced
+                let ng = match self.avg_arity as usize {
+                    d if d > 0 => rng.gen_range(0..d * 2),
+                    _ => 0,
+                };
+
+                let m = max(first as i32, (group_id as i32) - (ngroups as i32) / (proximity as i32)) as usize;
+                for _ in 0..ng {
+                    gqueue.push((rng.gen_range(m..group_id + 1), 1));
+                }
             }
 
-            if ngen > 0 {
-                let group_id = memo.groups.len();
-                memo.groups.push(RawGroup { exprs: exprs, id: group_id });
+            // Add at least this group, so that it is referenced or the last
+            gqueue.push((group_id, depth));
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = StreamGroup;
+
+    fn next(&mut self) -> Option<StreamGroup> {
+        while self.ready.is_empty() && self.root < self.config.roots {
+            self.step();
+        }
+        self.ready.pop_front()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RawMemo {
+    pub exprs: Vec<RawExpr>,
+    pub groups: Vec<RawGroup>,
+    pub entries: Vec<usize>,
+    pub phys: Vec<RawPhysExpr>,
+    pub scalars: Vec<RawScalar>,
+    pub cycles: Vec<usize>, // expressions that close a cycle
+    pub merges: Vec<(usize, usize)>, // groups that become equal due to duplicate expressions
+    pub commuted: Vec<(usize, usize)>, // joins and their copies with swapped children
+}
+
+impl RawMemo {
+    pub fn new(config: &GeneratorConfig, rng: ChaCha8Rng) -> Self {
+        info!("target: {} groups, {} expressions/group, {} roots", config.groups, config.exprs, config.roots);
+
+        let start = Instant::now();
+
+        let mut memo = RawMemo::default();
+        let mut generator = Generator::new(config, rng);
+        for group in generator.by_ref() {
+            memo.push_stream(group, 0, 0);
+        }
+        memo.entries = generator.entries().clone();
+
+        info!(
+            "result: {} groups, {} expressions, {} roots, depth {} ({:?})",
+            memo.groups.len(),
+            memo.exprs.len(),
+            memo.entries.len(),
+            memo.depth(),
+            start.elapsed(),
+        );
+
+        memo
+    }
+
+    /// Generate the memo in independent blocks, each from its own ChaCha stream of the seed, in
+    /// parallel. Blocks are stitched in order, so the memo depends on the number of blocks but
+    /// not on the number of threads. With a single root, the roots of blocks are joined pairwise.
+    pub fn new_blocks(config: &GeneratorConfig, seed: u64, threads: usize) -> Self {
+        let nblocks = config.blocks;
+        info!("target: {} groups, {} expressions/group, {} roots, {} blocks", config.groups, config.exprs, config.roots, nblocks);
 
-                // While we don't have enough groups, collect operands for future expressions
-                if group_id < ngroups && dag {
-                    // replenish groups to be referenced
-                    let ng = match nexprs * tot / cnt {
-                        d if d > 0 => rng.gen_range(0..d * 2),
-                        _ => 0,
+        let start = Instant::now();
+
+        let next = AtomicUsize::new(0);
+        let mut blocks: Vec<(usize, Vec<StreamGroup>, Vec<usize>)> = thread::scope(|s| {
+            let workers: Vec<_> = (0..threads.clamp(1, nblocks)).map(|_| s.spawn(|| {
+                let mut done = vec![];
+                loop {
+                    let b = next.fetch_add(1, Ordering::Relaxed);
+                    if b >= nblocks {
+                        break done;
+                    }
+                    let mut block = config.clone();
+                    block.groups = config.groups * (b + 1) / nblocks - config.groups * b / nblocks;
+                    block.roots = match config.roots {
+                        1 => 1,
+                        r => r * (b + 1) / nblocks - r * b / nblocks,
                     };
+                    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                    rng.set_stream(b as u64);
+                    let mut generator = Generator::new(&block, rng);
+                    let groups = generator.by_ref().collect();
+                    done.push((b, groups, generator.entries().clone()));
+                }
+            })).collect();
+            workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+        });
+        blocks.sort_by_key(|b| b.0);
+
+        let mut memo = RawMemo::default();
+        for (_, groups, entries) in blocks {
+            let (goff, eoff) = (memo.groups.len(), memo.exprs.len());
+            for group in groups {
+                memo.push_stream(group, goff, eoff);
+            }
+            memo.entries.extend(entries.iter().map(|e| e + goff));
+        }
 
-                    let m = max(0, (group_id as i32) - (ngroups as i32) / proximity) as usize;
-                    for _ in 0..ng {
-                        gqueue.push(rng.gen_range(m..group_id + 1));
+        if config.roots == 1 {
+            // join the roots of blocks pairwise into a single root
+            while memo.entries.len() > 1 {
+                let mut joined = vec![];
+                for pair in memo.entries.chunks(2) {
+                    if pair.len() < 2 {
+                        joined.push(pair[0]);
+                        continue;
+                    }
+                    let (j, g) = (memo.exprs.len(), memo.groups.len());
+                    memo.exprs.push(RawExpr { op: OP_JOIN, children: pair.to_vec(), pred: None, data: j, extra: vec![] });
+                    memo.groups.push(RawGroup { exprs: vec![j], id: g, ..Default::default() });
+                    joined.push(g);
+                }
+                memo.entries = joined;
+            }
+        }
+
+        info!(
+            "result: {} groups, {} expressions, {} roots, depth {} ({:?})",
+            memo.groups.len(),
+            memo.exprs.len(),
+            memo.entries.len(),
+            memo.depth(),
+            start.elapsed(),
+        );
+
+        memo
+    }
+
+    /// Append a generated group, with group and expression ids shifted by the given offsets.
+    fn push_stream(&mut self, group: StreamGroup, goff: usize, eoff: usize) {
+        let mut exprs = vec![];
+        for (j, mut e) in group.exprs {
+            let j = j + eoff;
+            e.data += eoff;
+            for c in e.children.iter_mut() {
+                *c += goff;
+            }
+            // expressions of extra scan groups are numbered before those of their parent
+            if j >= self.exprs.len() {
+                self.exprs.resize(j + 1, RawExpr::default());
+            }
+            self.exprs[j] = e;
+            exprs.push(j);
+        }
+        self.groups.push(RawGroup { exprs, id: group.id + goff, ..Default::default() });
+    }
+
+    pub fn join_graph(graph: JoinGraph, nrels: usize, mut rng: ChaCha8Rng) -> Self {
+        assert!(nrels > 0 && nrels < 64);
+
+        info!("target: {} relations", nrels);
+
+        let start = Instant::now();
+
+        // adjacency as bitmasks
+        let mut adj: Vec<u64> = vec![0; nrels];
+        let mut edge = |a: usize, b: usize| {
+            adj[a] |= 1 << b;
+            adj[b] |= 1 << a;
+        };
+        for i in 1..nrels {
+            match graph {
+                JoinGraph::Chain | JoinGraph::Cycle => edge(i - 1, i),
+                JoinGraph::Star => edge(0, i),
+                JoinGraph::Clique => (0..i).for_each(|j| edge(j, i)),
+                JoinGraph::Random => {
+                    // random spanning tree, plus some extra edges
+                    edge(rng.gen_range(0..i), i);
+                    for j in 0..i {
+                        if rng.gen_bool(0.2) {
+                            edge(j, i);
+                        }
                     }
                 }
+            }
+        }
+        if graph == JoinGraph::Cycle && nrels > 2 {
+            edge(0, nrels - 1);
+        }
 
-                // Add at least this group, so that it is referenced or the last
-                gqueue.push(group_id);
+        let neighbors = |set: u64| -> u64 {
+            (0..nrels).filter(|i| set & (1 << i) != 0).fold(0, |n, i| n | adj[i])
+        };
+        let connected = |set: u64| -> bool {
+            let mut reached = set & set.wrapping_neg();
+            loop {
+                let next = reached | (neighbors(reached) & set);
+                if next == reached {
+                    return reached == set;
+                }
+                reached = next;
             }
+        };
+
+        let mut memo = RawMemo::default();
+
+        // subsets are numerically larger than their parts, so children come first
+        let mut group_of: HashMap<u64, usize> = HashMap::new();
+        for set in 1..(1u64 << nrels) {
+            if !connected(set) {
+                continue;
+            }
+
+            let mut exprs = vec![];
+            if set.count_ones() == 1 {
+                exprs.push(memo.exprs.len());
+                memo.exprs.push(RawExpr { op: OP_SCAN, children: vec![], pred: None, data: memo.exprs.len(), extra: vec![] });
+            } else {
+                let mut left = (set - 1) & set;
+                while left > 0 {
+                    let right = set ^ left;
+                    if let (Some(l), Some(r)) = (group_of.get(&left), group_of.get(&right)) {
+                        if neighbors(left) & right != 0 {
+                            exprs.push(memo.exprs.len());
+                            memo.exprs.push(RawExpr { op: OP_JOIN, children: vec![*l, *r], pred: None, data: memo.exprs.len(), extra: vec![] });
+                        }
+                    }
+                    left = (left - 1) & set;
+                }
+            }
+
+            let group_id = memo.groups.len();
+            group_of.insert(set, group_id);
+            memo.groups.push(RawGroup { exprs, id: group_id, ..Default::default() });
         }
 
-        memo.entry = gqueue[0];
+        memo.entries.push(memo.groups.len() - 1);
+
+        // known sizes of the fully explored memo (joins in both orders)
+        let n = nrels as u64;
+        let expected = match graph {
+            JoinGraph::Chain => Some((n * (n + 1) / 2, (n * n * n - n) / 3)),
+            JoinGraph::Star => Some(((1 << (n - 1)) + n - 1, (n - 1) * (1 << (n - 1)))),
+            JoinGraph::Cycle if n > 2 => Some((n * n - n + 1, n * n * n - 2 * n * n + n)),
+            JoinGraph::Clique => Some(((1 << n) - 1, 3u64.pow(n as u32) + 1 - (1 << (n + 1)))),
+            _ => None,
+        };
+        if let Some((groups, joins)) = expected {
+            info!("expected: {} groups, {} expressions", groups, joins + n);
+        }
 
         info!(
-            "result: {} groups, {} expressions ({:?})",
+            "result: {} groups, {} expressions, depth {} ({:?})",
             memo.groups.len(),
             memo.exprs.len(),
+            memo.depth(),
             start.elapsed(),
         );
 
@@ -150,7 +616,7 @@ impl RawMemo {
                     g.exprs[chunk-1..].to_vec()
                 };
                 groups[i].exprs = upd;
-                groups.push(RawGroup{ exprs: rest, id: gid })
+                groups.push(RawGroup{ exprs: rest, id: gid, ..Default::default() })
             }
             i = i+1;
         }
@@ -158,8 +624,395 @@ impl RawMemo {
         RawMemo {
             exprs: self.exprs.clone(),
             groups: groups,
-            entry: self.entry,
+            entries: self.entries.clone(),
+            phys: self.phys.clone(),
+            scalars: self.scalars.clone(),
+            cycles: self.cycles.clone(),
+            merges: self.merges.clone(),
+            commuted: self.commuted.clone(),
+        }
+    }
+
+    /// Add `ncycles` expressions that make the group graph cyclic, as rules such as
+    /// `Filter(true, G) = G` would: a filter on the group itself, or on a parent group
+    /// to one of its children.
+    pub fn inject_cycles(&mut self, ncycles: usize, mut rng: ChaCha8Rng) {
+        let mut mutual = 0;
+        for _ in 0..ncycles {
+            let parent = rng.gen_range(0..self.groups.len());
+            let e = &self.exprs[*self.groups[parent].exprs.choose(&mut rng).unwrap()];
+            let group = match e.children.choose(&mut rng) {
+                Some(child) => {
+                    mutual += 1;
+                    *child
+                }
+                None => parent,
+            };
+
+            let expr_id = self.exprs.len();
+            self.exprs.push(RawExpr { op: OP_FILTER, children: vec![parent], pred: None, data: expr_id, extra: vec![] });
+            self.groups[group].exprs.push(expr_id);
+            self.cycles.push(expr_id);
+        }
+
+        info!("cycles: {} self, {} mutual", ncycles - mutual, mutual);
+    }
+
+    /// Add `ndups` copies of existing expressions, with the same payload and children, to
+    /// other groups at random positions, so that these groups must be merged. Half of them
+    /// also copy a parent expression over the merged groups, so that merges cascade.
+    pub fn inject_duplicates(&mut self, ndups: usize, mut rng: ChaCha8Rng) {
+        let before = self.exprs.len();
+        let mut cascades = 0;
+        for _ in 0..ndups {
+            let j = rng.gen_range(0..self.exprs.len());
+            let children = self.exprs[j].children.clone();
+            let Some(target) = self.duplicate(j, &children, &mut rng) else {
+                continue;
+            };
+
+            if rng.gen_bool(0.5) {
+                // copy a parent over the target, which must then be merged with its original
+                let source = self.group_of(j);
+                let parents: Vec<usize> = (0..self.exprs.len())
+                    .filter(|p| self.exprs[*p].children.contains(&source) && !self.cycles.contains(p))
+                    .collect();
+                if let Some(p) = parents.choose(&mut rng) {
+                    let children: Vec<usize> = self.exprs[*p].children.iter()
+                        .map(|c| if *c == source { target } else { *c })
+                        .collect();
+                    if self.duplicate(*p, &children, &mut rng).is_some() {
+                        cascades += 1;
+                    }
+                }
+            }
+        }
+
+        info!("duplicates: {} expressions, {} cascading", self.exprs.len() - before, cascades);
+    }
+
+    /// Copy expression `j` with the given children to some other unrelated group.
+    fn duplicate(&mut self, j: usize, children: &[usize], rng: &mut ChaCha8Rng) -> Option<usize> {
+        let target = self.unrelated(j, children, rng)?;
+        self.insert_copy(j, children, target, rng);
+        self.merges.push((self.group_of(j), target));
+
+        Some(target)
+    }
+
+    /// Some group that can hold a copy of expression `j` with the given children, such
+    /// that merging it with the group of `j` does not make the memo cyclic.
+    fn unrelated(&self, j: usize, children: &[usize], rng: &mut ChaCha8Rng) -> Option<usize> {
+        if self.cycles.contains(&j) {
+            return None;
+        }
+        let source = self.group_of(j);
+        let lowest = children.iter().map(|c| c + 1).max().unwrap_or(0);
+        if lowest >= self.groups.len() {
+            return None;
+        }
+
+        (0..10)
+            .map(|_| rng.gen_range(lowest..self.groups.len()))
+            .find(|t| *t != source && !self.reaches(*t, source) && !self.reaches(source, *t))
+    }
+
+    /// Insert a copy of expression `j`, with the same payload, at a random position of `target`.
+    fn insert_copy(&mut self, j: usize, children: &[usize], target: usize, rng: &mut ChaCha8Rng) -> usize {
+        let expr_id = self.exprs.len();
+        self.exprs.push(RawExpr { children: children.to_vec(), ..self.exprs[j].clone() });
+        let pos = rng.gen_range(0..self.groups[target].exprs.len() + 1);
+        self.groups[target].exprs.insert(pos, expr_id);
+        expr_id
+    }
+
+    /// Add copies of `ncommuted` joins with swapped children and the same payload, either
+    /// in the same group or in some other unrelated group. These are duplicates only for a
+    /// memo that canonicalizes commutative operators.
+    pub fn commute(&mut self, ncommuted: usize, into: CommuteInto, mut rng: ChaCha8Rng) {
+        let joins: Vec<usize> = (0..self.exprs.len())
+            .filter(|j| {
+                let e = &self.exprs[*j];
+                e.op == OP_JOIN && e.children[0] != e.children[1] && !self.cycles.contains(j)
+            })
+            .collect();
+
+        for j in joins.choose_multiple(&mut rng, ncommuted).cloned().collect::<Vec<_>>() {
+            let mut children = self.exprs[j].children.clone();
+            children.reverse();
+            let target = match into {
+                CommuteInto::Same => Some(self.group_of(j)),
+                CommuteInto::Other => self.unrelated(j, &children, &mut rng),
+            };
+            if let Some(target) = target {
+                let copy = self.insert_copy(j, &children, target, &mut rng);
+                self.commuted.push((j, copy));
+            }
+        }
+
+        info!("commute: {} of {} joins copied with swapped children", self.commuted.len(), joins.len());
+    }
+
+    /// Require the merges that a memo canonicalizing commutative operators would do.
+    pub fn merge_commuted(&mut self) {
+        for (j, copy) in self.commuted.clone() {
+            let (g, h) = (self.group_of(j), self.group_of(copy));
+            if g != h {
+                self.merges.push((g, h));
+            }
+        }
+    }
+
+    /// Depth of the deepest root, in groups. Children must have lower ids than their parents.
+    pub fn depth(&self) -> usize {
+        let mut depth = vec![0; self.groups.len()];
+        for (g, group) in self.groups.iter().enumerate() {
+            depth[g] = 1 + group.exprs.iter()
+                .flat_map(|e| self.exprs[*e].children.iter().map(|c| depth[*c]))
+                .max().unwrap_or(0);
+        }
+        self.entries.iter().map(|e| depth[*e]).max().unwrap_or(0)
+    }
+
+    pub fn group_of(&self, j: usize) -> usize {
+        self.groups.iter().position(|g| g.exprs.contains(&j)).unwrap()
+    }
+
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(g) = stack.pop() {
+            if g == to {
+                return true;
+            }
+            if visited.insert(g) {
+                for e in self.groups[g].exprs.iter() {
+                    stack.extend(self.exprs[*e].children.iter());
+                }
+            }
+        }
+        false
+    }
+
+    /// Sorted payloads expected in group `g`, after all required merges.
+    pub fn expected(&self, g: usize) -> Vec<usize> {
+        // equivalence class of g, by union-find over the required merges
+        let mut parent: HashMap<usize, usize> = HashMap::new();
+        fn find(parent: &mut HashMap<usize, usize>, g: usize) -> usize {
+            match parent.get(&g).copied() {
+                Some(p) if p != g => {
+                    let root = find(parent, p);
+                    parent.insert(g, root);
+                    root
+                }
+                _ => g,
+            }
+        }
+        for (a, b) in self.merges.iter() {
+            let (ra, rb) = (find(&mut parent, *a), find(&mut parent, *b));
+            if ra != rb {
+                parent.insert(ra, rb);
+            }
+        }
+        let root = find(&mut parent, g);
+        let mut class = vec![g];
+        for (a, b) in self.merges.iter() {
+            for x in [*a, *b] {
+                if x != g && !class.contains(&x) && find(&mut parent, x) == root {
+                    class.push(x);
+                }
+            }
+        }
+
+        let mut payloads: Vec<usize> = class.iter()
+            .flat_map(|c| self.groups[*c].exprs.iter().map(|e| self.exprs[*e].data))
+            .collect();
+        payloads.sort();
+        payloads.dedup();
+        payloads
+    }
+
+    /// Move expressions that close cycles to the end, as their groups need to exist before
+    /// they are inserted. They are inserted with an expression that is already in the group.
+    pub fn defer_cycles(&self) -> RawMemo {
+        let mut memo = self.clone();
+        for e in self.cycles.iter() {
+            let g = memo.groups.iter().position(|g| g.exprs.contains(e)).unwrap();
+            memo.groups[g].exprs.retain(|i| i != e);
+            let first = memo.groups[g].exprs[0];
+            memo.groups.push(RawGroup { exprs: vec![first, *e], id: g, ..Default::default() });
+        }
+        memo
+    }
+
+    /// Add `nfields` random fields to the payload of each expression.
+    pub fn add_payload(&mut self, nfields: usize, mut rng: ChaCha8Rng) {
+        for e in self.exprs.iter_mut() {
+            e.extra = (0..nfields).map(|_| rng.gen()).collect();
+        }
+
+        info!("payload: {} extra fields in {} expressions", nfields, self.exprs.len());
+    }
+
+    /// Add a scalar predicate tree, up to `depth` levels of AND/OR over comparisons
+    /// of columns with constants, to each expression. With probability `sharing`,
+    /// a predicate subtree already used by some other expression is reused.
+    pub fn add_scalars(&mut self, depth: usize, sharing: f64, mut rng: ChaCha8Rng) {
+        let start = Instant::now();
+
+        let mut preds: Vec<usize> = vec![]; // boolean subtrees that can be shared
+        for j in 0..self.exprs.len() {
+            let root = self.gen_predicate(depth, sharing, &mut preds, &mut rng);
+            self.exprs[j].pred = Some(root);
+        }
+
+        info!(
+            "scalars: {} scalar expressions, {} predicates ({:?})",
+            self.scalars.len(),
+            preds.len(),
+            start.elapsed(),
+        );
+    }
+
+    fn gen_predicate(&mut self, depth: usize, sharing: f64, preds: &mut Vec<usize>, rng: &mut ChaCha8Rng) -> usize {
+        if !preds.is_empty() && rng.gen_bool(sharing) {
+            return preds[rng.gen_range(0..preds.len())];
+        }
+
+        let (op, children) = if depth == 0 || rng.gen_bool(0.5) {
+            // comparison of a column with a constant
+            let column = self.scalars.len();
+            self.scalars.push(RawScalar { op: SCALAR_COLUMN, children: vec![], value: rng.gen_range(0..16) });
+            let constant = self.scalars.len();
+            self.scalars.push(RawScalar { op: SCALAR_CONST, children: vec![], value: rng.gen_range(0..100) });
+            (*[SCALAR_EQ, SCALAR_LT].choose(rng).unwrap(), vec![column, constant])
+        } else {
+            let left = self.gen_predicate(depth - 1, sharing, preds, rng);
+            let right = self.gen_predicate(depth - 1, sharing, preds, rng);
+            (*[SCALAR_AND, SCALAR_OR].choose(rng).unwrap(), vec![left, right])
+        };
+
+        let id = self.scalars.len();
+        self.scalars.push(RawScalar { op, children, value: 0 });
+        preds.push(id);
+        id
+    }
+
+    /// Add physical alternatives for each logical expression, with sort order on
+    /// one of `nprops` keys as the only physical property, sort enforcers for each
+    /// required property in each group, and the winners for each required property.
+    pub fn add_physical(&mut self, nprops: usize, mut rng: ChaCha8Rng) {
+        let start = Instant::now();
+
+        // Implement logical expressions, collecting properties required from each group
+        let mut required: Vec<BTreeSet<usize>> = vec![BTreeSet::from([0]); self.groups.len()];
+        for (gid, g) in self.groups.iter_mut().enumerate() {
+            for j in g.exprs.iter() {
+                let e = &self.exprs[*j];
+                if e.children.iter().any(|c| *c >= gid) {
+                    // not ordered bottom-up, cannot be costed
+                    continue;
+                }
+                let mut alts = vec![];
+                match e.op {
+                    OP_SCAN => alts.push((PHYS_TABLE_SCAN, vec![], 0)),
+                    OP_FILTER => {
+                        alts.push((PHYS_FILTER, vec![(e.children[0], 0)], 0));
+                        if nprops > 0 {
+                            // order preserving filter
+                            let k = rng.gen_range(1..nprops + 1);
+                            alts.push((PHYS_FILTER, vec![(e.children[0], k)], k));
+                        }
+                    }
+                    OP_JOIN => {
+                        alts.push((PHYS_HASH_JOIN, vec![(e.children[0], 0), (e.children[1], 0)], 0));
+                        alts.push((PHYS_NESTED_LOOP, vec![(e.children[0], 0), (e.children[1], 0)], 0));
+                        if nprops > 0 {
+                            let k = rng.gen_range(1..nprops + 1);
+                            alts.push((PHYS_MERGE_JOIN, vec![(e.children[0], k), (e.children[1], k)], k));
+                        }
+                    }
+                    OP_PROJECT => {
+                        alts.push((PHYS_PROJECT, vec![(e.children[0], 0)], 0));
+                        if nprops > 0 {
+                            let k = rng.gen_range(1..nprops + 1);
+                            alts.push((PHYS_PROJECT, vec![(e.children[0], k)], k));
+                        }
+                    }
+                    OP_AGGREGATE => alts.push((PHYS_HASH_AGGREGATE, vec![(e.children[0], 0)], 0)),
+                    _ => unreachable!(),
+                }
+                for (op, children, prop) in alts {
+                    for (c, p) in children.iter() {
+                        required[*c].insert(*p);
+                    }
+                    g.phys.push(self.phys.len());
+                    self.phys.push(RawPhysExpr {
+                        op,
+                        expr: Some(*j),
+                        children,
+                        prop,
+                        cost: rng.gen_range(1..100), // local cost only, until inputs are known
+                    });
+                }
+            }
         }
+
+        // Cost bottom-up, adding enforcers and picking winners for each required property
+        for (gid, required) in required.iter().enumerate() {
+            let mut winners: BTreeMap<usize, usize> = BTreeMap::new();
+            for p in self.groups[gid].phys.clone() {
+                // an input without a winner for the required property cannot be implemented
+                let inputs: Option<Vec<u64>> = self.phys[p].children.iter()
+                    .map(|(c, cp)| self.groups[*c].winners.get(cp).map(|w| self.phys[*w].cost))
+                    .collect();
+                let Some(inputs) = inputs else {
+                    continue;
+                };
+                let cost = self.phys[p].cost + inputs.iter().sum::<u64>();
+                self.phys[p].cost = cost;
+
+                let prop = self.phys[p].prop;
+                for r in [0, prop] {
+                    match winners.get(&r) {
+                        Some(w) if self.phys[*w].cost <= cost => {}
+                        _ => {
+                            winners.insert(r, p);
+                        }
+                    }
+                }
+            }
+
+            if let Some(any) = winners.get(&0).copied() {
+                for r in required.iter().filter(|r| **r != 0) {
+                    let cost = self.phys[any].cost + rng.gen_range(1..100);
+                    let p = self.phys.len();
+                    self.groups[gid].phys.push(p);
+                    self.phys.push(RawPhysExpr {
+                        op: PHYS_SORT,
+                        expr: None,
+                        children: vec![(gid, 0)],
+                        prop: *r,
+                        cost,
+                    });
+                    match winners.get(r) {
+                        Some(w) if self.phys[*w].cost <= cost => {}
+                        _ => {
+                            winners.insert(*r, p);
+                        }
+                    }
+                }
+            }
+
+            self.groups[gid].winners = winners;
+        }
+
+        info!(
+            "physical: {} expressions, {} winners ({:?})",
+            self.phys.len(),
+            self.groups.iter().map(|g| g.winners.len()).sum::<usize>(),
+            start.elapsed(),
+        );
     }
 
     pub fn dump_dot(&self, writer: &mut Box<dyn Write>) -> std::io::Result<()> {
@@ -173,8 +1026,9 @@ impl RawMemo {
         }
         for (i, e) in self.exprs.iter().enumerate() {
             writeln!(writer, "\"e{}\" [shape=oval,style=filled,color={}];", i, e.op+1)?;
+            let style = if self.cycles.contains(&i) { " [color=red]" } else { "" };
             for c in e.children.iter() {
-                writeln!(writer, "\"e{}\" -> \"g{}\";", i, c)?;
+                writeln!(writer, "\"e{}\" -> \"g{}\"{};", i, c, style)?;
             }
         }
         writeln!(writer, "}}")
@@ -185,10 +1039,10 @@ impl RawMemo {
         for (i, g) in self.groups.iter().enumerate() {
             for e in g.exprs.iter() {
                 let expr = &self.exprs[*e];
-                match expr.op {
-                    0 => writeln!(writer, "{},{},0,-1,-1", i, e)?,
-                    1 => writeln!(writer, "{},{},1,{},-1", i, e, expr.children[0])?,
-                    2 => writeln!(writer, "{},{},2,{},{}", i, e, expr.children[0], expr.children[1])?,
+                match expr.children.len() {
+                    0 => writeln!(writer, "{},{},{},-1,-1", i, e, expr.op)?,
+                    1 => writeln!(writer, "{},{},{},{},-1", i, e, expr.op, expr.children[0])?,
+                    2 => writeln!(writer, "{},{},{},{},{}", i, e, expr.op, expr.children[0], expr.children[1])?,
                     _ => panic!("unknown operator"),
                 }
             }
@@ -11,11 +11,12 @@ use j4rs::{ClasspathEntry, Instance, InvocationArg, Jvm, JvmBuilder, Null};
 pub struct BenchCalcite {
     jvm: Jvm,
     bridge: Instance,
-    relsubsets: Vec<Instance>
+    relsubsets: Vec<Instance>,
+    canonicalize: bool,
 }
 
 impl BenchCalcite {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new(canonicalize: bool) -> Result<Self, Box<dyn Error>> {
         let entry = ClasspathEntry::new("./bridge/target/calcite-bridge-1.0-SNAPSHOT-jar-with-dependencies.jar");
         let jvm: Jvm = JvmBuilder::new()
             .classpath_entry(entry)
@@ -28,6 +29,7 @@ impl BenchCalcite {
             jvm,
             bridge,
             relsubsets: vec![],
+            canonicalize,
         })
     }
 }
@@ -37,37 +39,48 @@ impl Benchmark for BenchCalcite {
         let mut hist =
             Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
 
+        if !memo.scalars.is_empty() {
+            warn!("scalar predicates not supported, using unique literals");
+        }
+        if memo.exprs.iter().any(|e| !e.extra.is_empty()) {
+            warn!("extra payload not supported, ignored");
+        }
+
         for (i,g) in memo.groups.iter().enumerate() {
             let start = Instant::now();
             let mut relsubset = InvocationArg::create_null(Null::Of("org.apache.calcite.rel.RelNode"))?;
 
             for (j, eidx) in g.exprs.iter().enumerate() {
                 let e = &memo.exprs[*eidx];
+                let children = match self.canonicalize {
+                    true => e.canonical_children(),
+                    false => e.children.clone(),
+                };
 
                 // build expressions with unique predicates
                 let inst = match e.op {
                     0 => self.jvm.invoke(
                         &self.bridge, "addScan",
                         &vec![
-                            InvocationArg::try_from(*eidx as i32)?.into_primitive()?,
+                            InvocationArg::try_from(e.data as i32)?.into_primitive()?,
                             relsubset
                         ])?,
                     1 => self.jvm.invoke(
                         &self.bridge, "addFilter",
                         &vec![
-                            InvocationArg::try_from(*eidx as i32)?.into_primitive()?,
-                            InvocationArg::try_from(self.jvm.clone_instance(&self.relsubsets[e.children[0]]))?,
+                            InvocationArg::try_from(e.data as i32)?.into_primitive()?,
+                            InvocationArg::try_from(self.jvm.clone_instance(&self.relsubsets[children[0]]))?,
                             relsubset
                         ])?,
                     2 => self.jvm.invoke(
                         &self.bridge, "addJoin",
                         &vec![
-                            InvocationArg::try_from(*eidx as i32)?.into_primitive()?,
-                            InvocationArg::try_from(self.jvm.clone_instance(&self.relsubsets[e.children[0]]))?,
-                            InvocationArg::try_from(self.jvm.clone_instance(&self.relsubsets[e.children[1]]))?,
+                            InvocationArg::try_from(e.data as i32)?.into_primitive()?,
+                            InvocationArg::try_from(self.jvm.clone_instance(&self.relsubsets[children[0]]))?,
+                            InvocationArg::try_from(self.jvm.clone_instance(&self.relsubsets[children[1]]))?,
                             relsubset
                         ])?,
-                    _ => unreachable!(),
+                    op => return Err(format!("operator {} not supported", op).into()),
                 };
                 if j == 0 {
                     if i == 0 {
@@ -114,7 +127,8 @@ impl Benchmark for BenchCalcite {
             }
 
             ids.sort();
-            assert_eq!(ids, memo.groups[g].exprs, "incorrect memo")
+            ids.dedup();
+            assert_eq!(ids, memo.expected(g), "incorrect memo")
         }
 
         Ok(hist)
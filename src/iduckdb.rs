@@ -0,0 +1,413 @@
+use crate::generator::{Generator, RawExpr, RawMemo, OP_FILTER, OP_JOIN};
+use crate::{log_summary, Benchmark};
+use clap::ValueEnum;
+use duckdb::{params, Appender, Connection};
+use hdrhistogram::Histogram;
+use log::{debug, info, warn};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use std::cmp::{max, min};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+/// An expression read back from the table: id, operator and children.
+type Row = (usize, usize, Vec<usize>);
+
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum MatchMode { Navigational, Join, Both }
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS expr (id BIGINT, group_id BIGINT, op INTEGER, data BIGINT, child0 BIGINT, child1 BIGINT);
+CREATE TABLE IF NOT EXISTS fwd (id BIGINT, target BIGINT);
+"#;
+
+/// Filter expressions joined with the Join expressions of their child group, as the bindings of
+/// the rule, in a single query.
+const MATCH_JOIN: &str = r#"
+SELECT f.id, j.id, j.child0, j.child1
+FROM expr f JOIN expr j ON j.group_id = f.child0
+WHERE f.op = ? AND j.op = ?
+"#;
+
+/// A memo in an embedded DuckDB database, with a row for each expression in a single table.
+/// Rows are added with an appender, so duplicates are found with an index kept in memory.
+pub struct BenchDuckDb {
+    conn: Connection,
+    index: Index,
+    entries: Vec<usize>,
+    match_mode: MatchMode,
+}
+
+/// Group of each expression, by operator, payload and children, and the forwarding of merged
+/// groups, also stored in the database so that a memo left by a previous run can be retrieved.
+struct Index {
+    keys: HashMap<(usize, usize, Vec<usize>), usize>,
+    fwd: HashMap<usize, usize>,
+    canonicalize: bool,
+}
+
+impl BenchDuckDb {
+    pub fn new(database: &str, canonicalize: bool, match_mode: MatchMode) -> Result<Self, Box<dyn Error>> {
+        let conn = match database {
+            ":memory:" => Connection::open_in_memory()?,
+            path => Connection::open(path)?,
+        };
+        conn.execute_batch(SCHEMA)?;
+
+        let fwd: HashMap<usize, usize> = conn.prepare("SELECT id, target FROM fwd")?
+            .query_map([], |r| Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?)))?
+            .collect::<Result<_, _>>()?;
+        // children are kept current by merges, so a duplicate key only names an equivalent group
+        let keys = conn.prepare("SELECT op, data, child0, child1, group_id FROM expr")?
+            .query_map([], |r| {
+                let columns = [r.get::<_, Option<usize>>(2)?, r.get::<_, Option<usize>>(3)?];
+                let key = (r.get::<_, usize>(0)?, r.get::<_, usize>(1)?, columns.iter().flatten().copied().collect());
+                Ok((key, r.get::<_, usize>(4)?))
+            })?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        debug!("duckdb: {} expressions and {} merged groups loaded", keys.len(), fwd.len());
+
+        Ok(BenchDuckDb {
+            conn,
+            index: Index { keys, fwd, canonicalize },
+            entries: vec![],
+            match_mode,
+        })
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch("DROP TABLE IF EXISTS expr; DROP TABLE IF EXISTS fwd;")?;
+        self.conn.execute_batch(SCHEMA)?;
+        self.index.keys.clear();
+        self.index.fwd.clear();
+        Ok(())
+    }
+
+    fn log_merged(&self) {
+        if !self.index.fwd.is_empty() {
+            info!("duckdb: {} groups merged", self.index.fwd.len());
+        }
+    }
+}
+
+impl Benchmark for BenchDuckDb {
+    fn add(&mut self, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        if memo.exprs.iter().any(|e| !e.extra.is_empty()) {
+            warn!("extra payload not supported, ignored");
+        }
+        if memo.exprs.iter().any(|e| e.pred.is_some()) {
+            warn!("scalar predicates not supported, ignored");
+        }
+
+        self.clear()?;
+
+        let mut app = self.conn.appender("expr")?;
+        for (i, g) in memo.groups.iter().enumerate() {
+            let start = Instant::now();
+
+            let exprs: Vec<&RawExpr> = g.exprs.iter().map(|j| &memo.exprs[*j]).collect();
+            self.index.insert_group(&self.conn, &mut app, i, &g.exprs, exprs)?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+        app.flush()?;
+
+        self.entries = memo.entries.iter().map(|e| self.index.find(*e)).collect();
+        self.log_merged();
+
+        Ok(hist)
+    }
+
+    fn add_stream(&mut self, groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        self.clear()?;
+
+        let mut app = self.conn.appender("expr")?;
+        for g in groups.by_ref() {
+            let start = Instant::now();
+
+            let eids: Vec<usize> = g.exprs.iter().map(|(j, _)| *j).collect();
+            self.index.insert_group(&self.conn, &mut app, g.id, &eids, g.exprs.iter().map(|(_, e)| e).collect())?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+        app.flush()?;
+
+        self.entries = groups.entries().clone();
+        self.log_merged();
+
+        Ok(hist)
+    }
+
+    fn retrieve(&mut self, mut rng: ChaCha8Rng, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        let mut stmt = self.conn.prepare("SELECT data FROM expr WHERE group_id = ?")?;
+
+        for g in (0..memo.groups.len()).chain((0..1000).map(|_| { rng.gen_range(0..memo.groups.len()) })) {
+
+            let start = Instant::now();
+
+            let mut ids = stmt.query_map([self.index.find(g)], |r| r.get::<_, usize>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids, memo.expected(g), "incorrect memo")
+        }
+
+        Ok(hist)
+    }
+
+    fn match_rules(&mut self) -> Result<Histogram<u64>, Box<dyn Error>> {
+        if self.match_mode == MatchMode::Join {
+            return self.match_join();
+        }
+
+        let mut info = MatchInfo {
+            visited_exprs: Default::default(),
+            visited_groups: Default::default(),
+            root_groups: Default::default(),
+            shared: 0,
+            hist: Histogram::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?,
+            last: Instant::now(),
+        };
+
+        for root in self.entries.clone() {
+            info.root_groups.clear();
+            self.explore_group(&mut info, root)?;
+        }
+
+        info!("match: {} roots, {} groups explored, {} shared with previous roots",
+            self.entries.len(), info.visited_groups.len(), info.shared);
+
+        if self.match_mode == MatchMode::Both {
+            let now = Instant::now();
+            let hist = self.match_join()?;
+            if hist.len() != info.hist.len() {
+                warn!("match: {} bindings with a join, {} navigating groups", hist.len(), info.hist.len());
+            }
+            log_summary(hist, "match (join)", now.elapsed(), false);
+        }
+
+        Ok(info.hist)
+    }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare_cached("SELECT data FROM expr WHERE group_id = ?")?;
+        let ids = stmt.query_map([self.index.find(g)], |r| r.get::<_, usize>(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.index.find(g) == self.index.find(h))
+    }
+}
+
+struct MatchInfo {
+    visited_exprs: HashSet<usize>,
+    visited_groups: HashSet<usize>,
+    root_groups: HashSet<usize>, // reached from the current root
+    shared: usize, // groups already explored from previous roots
+    hist: Histogram<u64>,
+    last: Instant,
+}
+
+impl Index {
+    /// Current group of a group id, following the forwarding of merged groups.
+    fn find(&self, mut gid: usize) -> usize {
+        while let Some(next) = self.fwd.get(&gid) {
+            gid = *next;
+        }
+        gid
+    }
+
+    fn forward(&mut self, conn: &Connection, from: usize, to: usize) -> Result<(), Box<dyn Error>> {
+        self.fwd.insert(from, to);
+        conn.execute("INSERT INTO fwd VALUES (?, ?)", params![from, to])?;
+        Ok(())
+    }
+
+    /// Append the expressions of group i, or find the group of a duplicate, merging groups when
+    /// expressions are found in more than one.
+    fn insert_group(&mut self, conn: &Connection, app: &mut Appender, i: usize, eids: &[usize], exprs: Vec<&RawExpr>) -> Result<(), Box<dyn Error>> {
+        let mut gid = i;
+        let mut owned = false; // some expression is already in gid
+        for (j, e) in exprs.iter().enumerate() {
+            let mut children: Vec<usize> = e.children.iter().map(|c| self.find(*c)).collect();
+            if self.canonicalize && e.op == OP_JOIN {
+                children.sort();
+            }
+            if children.len() > 2 {
+                return Err(format!("operator {} not supported", e.op).into());
+            }
+            let key = (e.op, e.data, children);
+
+            match self.keys.get(&key).map(|id| self.find(*id)) {
+                Some(id) if !owned => {
+                    // the group already exists, forward its id too
+                    if id != gid {
+                        self.forward(conn, gid, id)?;
+                    }
+                    gid = id;
+                    owned = true;
+                }
+                Some(id) => {
+                    if id != gid {
+                        gid = self.merge(conn, app, gid, id)?;
+                    }
+                }
+                None => {
+                    owned = true;
+                    app.append_row(params![eids[j], gid, key.0, key.1, key.2.first(), key.2.get(1)])?;
+                    self.keys.insert(key, gid);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge two groups into the one with the lowest id and update the children of expressions
+    /// with the other as a child, which may find more duplicates and thus more groups to merge.
+    fn merge(&mut self, conn: &Connection, app: &mut Appender, a: usize, b: usize) -> Result<usize, Box<dyn Error>> {
+        // rows are only visible to updates once appended
+        app.flush()?;
+
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.find(a), self.find(b));
+            if a == b {
+                continue;
+            }
+            let (winner, loser) = (min(a, b), max(a, b));
+
+            conn.execute("UPDATE expr SET group_id = ? WHERE group_id = ?", params![winner, loser])?;
+            self.forward(conn, loser, winner)?;
+
+            let parents = conn.prepare("SELECT id, group_id, op, data, child0, child1 FROM expr WHERE child0 = ?1 OR child1 = ?1")?
+                .query_map([loser], |r| Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?, r.get::<_, usize>(2)?,
+                    r.get::<_, usize>(3)?, [r.get::<_, Option<usize>>(4)?, r.get::<_, Option<usize>>(5)?])))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (id, owner, op, data, columns) in parents {
+                let old: Vec<usize> = columns.iter().flatten().copied().collect();
+                let mut children: Vec<usize> = old.iter().map(|c| if *c == loser { winner } else { *c }).collect();
+                if self.canonicalize && op == OP_JOIN {
+                    children.sort();
+                }
+                conn.execute("UPDATE expr SET child0 = ?, child1 = ? WHERE id = ?",
+                    params![children.first(), children.get(1), id])?;
+
+                self.keys.remove(&(op, data, old));
+                match self.keys.entry((op, data, children)) {
+                    Entry::Occupied(other) => {
+                        // the parent is now a duplicate of an expression in another group
+                        pending.push((owner, *other.get()));
+                    }
+                    Entry::Vacant(v) => {
+                        v.insert(owner);
+                    }
+                }
+            }
+        }
+
+        Ok(self.find(a))
+    }
+}
+
+impl BenchDuckDb {
+    /// Match the rule for all groups at once, with a join of the expression table with itself.
+    fn match_join(&mut self) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        let start = Instant::now();
+
+        let picks = self.conn.prepare(MATCH_JOIN)?
+            .query_map([OP_FILTER, OP_JOIN], |r| Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?,
+                r.get::<_, usize>(2)?, r.get::<_, usize>(3)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // small cheat, as with calcite: use the average time and the number of matches
+        let matches = picks.len() as u64;
+        if let Some(average) = (start.elapsed().as_nanos() as u64).checked_div(matches) {
+            if hist.record_n(average, matches).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+        debug!("found matches {:?}", picks);
+
+        Ok(hist)
+    }
+
+    fn explore_group(&mut self, info: &mut MatchInfo, group_id: usize) -> Result<(), Box<dyn Error>> {
+        if info.visited_groups.insert(group_id) {
+            info.root_groups.insert(group_id);
+            let exprs = self.group(group_id)?;
+            for (id, op, children) in exprs {
+                self.optimize_expression(info, id, op, children)?;
+            }
+        } else if info.root_groups.insert(group_id) {
+            info.shared += 1;
+        }
+
+        Ok(())
+    }
+
+    fn optimize_expression(&mut self, info: &mut MatchInfo, id: usize, op: usize, children: Vec<usize>) -> Result<(), Box<dyn Error>> {
+        if info.visited_exprs.insert(id) {
+
+            // explore children first
+            for c in children.iter() {
+                self.explore_group(info, *c)?;
+            }
+
+            // top_matches in optimize_expression task
+            let mut picks = vec![];
+            if op == OP_FILTER {
+
+                // match_and_pick_expr in apply_rule task
+                for (_, bot_op, bot_children) in self.group(children[0])? {
+                    if bot_op == OP_JOIN {
+                        picks.push(bot_children);
+
+                        let now = Instant::now();
+                        if info.hist.record(now.duration_since(info.last).as_nanos() as u64).is_err() {
+                            warn!("histogram overflow")
+                        }
+                        info.last = now;
+                    }
+                }
+            }
+            debug!("found matches {:?}", picks)
+        }
+
+        Ok(())
+    }
+
+    /// Identifier, operator and children of the expressions in a group.
+    fn group(&self, gid: usize) -> Result<Vec<Row>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare_cached("SELECT id, op, child0, child1 FROM expr WHERE group_id = ?")?;
+        let exprs = stmt.query_map([gid], |r| {
+            let children = [r.get::<_, Option<usize>>(2)?, r.get::<_, Option<usize>>(3)?];
+            Ok((r.get(0)?, r.get(1)?, children.into_iter().flatten().collect()))
+        })?.collect::<Result<_, _>>()?;
+        Ok(exprs)
+    }
+}
@@ -0,0 +1,807 @@
+use crate::generator::{Generator, RawExpr, RawMemo, RawScalar, OP_FILTER, OP_JOIN};
+use crate::Benchmark;
+use clap::ValueEnum;
+use hdrhistogram::Histogram;
+use log::{info, warn};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, ValueEnum, PartialEq)]
+pub enum Store { Redb, Sled, Rocksdb, Lmdb }
+
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum Durability { Group, None }
+
+/// Keys and values, in key order.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Operations of an embedded key-value store used by the memo. Writes are made between begin
+/// and commit, in a single write transaction for stores that have them, and are seen by reads
+/// before being committed.
+trait Kv {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    /// Keys and values starting with a prefix, in key order
+    fn scan(&self, prefix: &[u8]) -> Result<Entries, Box<dyn Error>>;
+    fn begin(&mut self) -> Result<(), Box<dyn Error>>;
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn delete(&mut self, key: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Commit the writes since begin, syncing them to disk if durable
+    fn commit(&mut self, durable: bool) -> Result<(), Box<dyn Error>>;
+}
+
+fn open(store: Store, path: &Path) -> Result<Box<dyn Kv>, Box<dyn Error>> {
+    match store {
+        #[cfg(feature = "redb")]
+        Store::Redb => Ok(Box::new(RedbKv::open(path)?)),
+        #[cfg(feature = "sled")]
+        Store::Sled => Ok(Box::new(SledKv(sled::open(path)?))),
+        #[cfg(feature = "rocksdb")]
+        Store::Rocksdb => Ok(Box::new(RocksKv::open(path)?)),
+        #[cfg(feature = "lmdb")]
+        Store::Lmdb => Ok(Box::new(LmdbKv::open(path)?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(format!("{} not enabled (build with the {} feature)", name(store), name(store)).into()),
+    }
+}
+
+fn name(store: Store) -> &'static str {
+    match store {
+        Store::Redb => "redb",
+        Store::Sled => "sled",
+        Store::Rocksdb => "rocksdb",
+        Store::Lmdb => "lmdb",
+    }
+}
+
+/// File marking a directory as created by memobench, so that the files in it can be removed.
+const MARKER: &str = ".memobench";
+
+/// Create a directory for the files of a store, or check that an existing one was created by
+/// memobench, so that nothing else is ever removed.
+fn own(path: &Path) -> Result<(), Box<dyn Error>> {
+    if path.exists() && !path.join(MARKER).exists() && (!path.is_dir() || fs::read_dir(path)?.next().is_some()) {
+        return Err(format!("{} is not an empty directory or one created by memobench", path.display()).into());
+    }
+    fs::create_dir_all(path)?;
+    fs::write(path.join(MARKER), "")?;
+    Ok(())
+}
+
+/// A memo in an embedded key-value store, with ordered keys:
+/// - g/<gid>/<eid> to each expression in a group, so that a group is read with a prefix scan
+/// - h/<hash>/<eid> to the group of each expression with a hash, to find duplicates
+/// - p/<gid>/<eid> to the group of each parent of a group, rewritten when the group is merged
+/// - f/<gid> to the group a merged group was forwarded to
+///
+/// Groups are stored as they were when the key was written and then forwarded, so that keys
+/// are not rewritten when the group of an expression is merged. The eid in hash keys tells
+/// apart expressions whose hashes collide, and is checked against the stored expression. Ids
+/// are big-endian, so that keys sort by id.
+pub struct BenchKv {
+    kv: Option<Box<dyn Kv>>, // closed while the files are removed
+    store: Store,
+    path: PathBuf, // directory owned by memobench, with the files of the store in a subdirectory
+    temporary: bool, // the default directory of this process, removed at the end
+    durability: Durability,
+    entries: Vec<usize>,
+    canonicalize: bool,
+    merged: bool, // groups have been merged, so ids need to be forwarded
+}
+
+impl BenchKv {
+    pub fn new(store: Store, path: Option<PathBuf>, durability: Durability, canonicalize: bool) -> Result<Self, Box<dyn Error>> {
+        // concurrent runs would otherwise remove each other's files
+        let temporary = path.is_none();
+        let path = path.unwrap_or_else(|| std::env::temp_dir().join(format!("memobench-{}-{}", name(store), std::process::id())));
+        own(&path)?;
+        let kv = open(store, &path.join(name(store)))?;
+
+        // a memo left by a previous run may have merged groups
+        let merged = !kv.scan(b"f/")?.is_empty();
+
+        info!("kv: {} in {}", name(store), path.join(name(store)).display());
+
+        Ok(BenchKv {
+            kv: Some(kv),
+            store,
+            path,
+            temporary,
+            durability,
+            entries: vec![],
+            canonicalize,
+            merged,
+        })
+    }
+
+    fn kv(&self) -> &dyn Kv {
+        self.kv.as_deref().unwrap()
+    }
+
+    fn kv_mut(&mut self) -> &mut dyn Kv {
+        self.kv.as_deref_mut().unwrap()
+    }
+
+    /// Remove the files of the store and open it again, empty.
+    fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        self.kv = None;
+        let files = self.path.join(name(self.store));
+        if files.is_dir() {
+            fs::remove_dir_all(&files)?;
+        } else if files.exists() {
+            fs::remove_file(&files)?;
+        }
+        self.kv = Some(open(self.store, &files)?);
+        self.merged = false;
+        Ok(())
+    }
+
+    fn log_merged(&self) -> Result<(), Box<dyn Error>> {
+        if self.merged {
+            info!("kv: {} groups merged", self.kv().scan(b"f/")?.len());
+        }
+        Ok(())
+    }
+
+    /// Add a group in a transaction, made durable if requested.
+    fn add_group(&mut self, i: usize, eids: &[usize], exprs: Vec<&RawExpr>, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        self.kv_mut().begin()?;
+        self.insert_group(i, eids, exprs, scalars)?;
+        let durable = self.durability == Durability::Group;
+        self.kv_mut().commit(durable)
+    }
+}
+
+impl Drop for BenchKv {
+    fn drop(&mut self) {
+        if self.temporary {
+            self.kv = None;
+            if let Err(e) = fs::remove_dir_all(&self.path) {
+                warn!("cannot remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl Benchmark for BenchKv {
+    fn add(&mut self, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        self.clear()?;
+
+        for (i, g) in memo.groups.iter().enumerate() {
+            let start = Instant::now();
+
+            let exprs: Vec<&RawExpr> = g.exprs.iter().map(|j| &memo.exprs[*j]).collect();
+            self.add_group(i, &g.exprs, exprs, &memo.scalars)?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+
+        self.entries = memo.entries.iter().map(|e| self.find(*e)).collect::<Result<_, _>>()?;
+        self.log_merged()?;
+
+        Ok(hist)
+    }
+
+    fn add_stream(&mut self, groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        self.clear()?;
+
+        for g in groups.by_ref() {
+            let start = Instant::now();
+
+            let eids: Vec<usize> = g.exprs.iter().map(|(j, _)| *j).collect();
+            self.add_group(g.id, &eids, g.exprs.iter().map(|(_, e)| e).collect(), &[])?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+
+        self.entries = groups.entries().clone();
+        self.log_merged()?;
+
+        Ok(hist)
+    }
+
+    fn retrieve(&mut self, mut rng: ChaCha8Rng, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        for g in (0..memo.groups.len()).chain((0..1000).map(|_| { rng.gen_range(0..memo.groups.len()) })) {
+
+            let start = Instant::now();
+
+            let group_expressions = self.kv().scan(&prefix(b'g', &[self.find(g)?]))?;
+
+            // do something with it
+            let mut ids: Vec<usize> = group_expressions.iter().map(|(_, body)| header(body).1 as usize).collect();
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids, memo.expected(g), "incorrect memo")
+        }
+
+        Ok(hist)
+    }
+
+    fn match_rules(&mut self) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut info = MatchInfo {
+            visited_exprs: Default::default(),
+            visited_groups: Default::default(),
+            root_groups: Default::default(),
+            shared: 0,
+            hist: Histogram::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?,
+            last: Instant::now(),
+        };
+
+        for root in self.entries.clone() {
+            info.root_groups.clear();
+            self.explore_group(&mut info, root)?;
+        }
+
+        info!("match: {} roots, {} groups explored, {} shared with previous roots",
+            self.entries.len(), info.visited_groups.len(), info.shared);
+
+        Ok(info.hist)
+    }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let group_expressions = self.kv().scan(&prefix(b'g', &[self.find(g)?]))?;
+        Ok(group_expressions.iter().map(|(_, body)| header(body).1 as usize).collect())
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.find(g)? == self.find(h)?)
+    }
+}
+
+struct MatchInfo {
+    visited_exprs: HashSet<usize>,
+    visited_groups: HashSet<usize>,
+    root_groups: HashSet<usize>, // reached from the current root
+    shared: usize, // groups already explored from previous roots
+    hist: Histogram<u64>,
+    last: Instant,
+}
+
+impl BenchKv {
+    /// Body of an expression, with children as currently merged, so that duplicates are equal.
+    fn body(&self, e: &RawExpr, scalars: &[RawScalar]) -> Result<(Vec<u8>, Vec<usize>), Box<dyn Error>> {
+        let mut children = e.children.iter().map(|c| self.find(*c)).collect::<Result<Vec<_>, _>>()?;
+        if self.canonicalize && e.op == OP_JOIN {
+            children.sort();
+        }
+        Ok((encode(e, &children, scalars), children))
+    }
+
+    /// Group of an expression equal to the body, other than the given one, by its hash and then
+    /// by its contents.
+    fn lookup(&self, body: &[u8], except: Option<usize>) -> Result<Option<usize>, Box<dyn Error>> {
+        for (key, gid) in self.kv().scan(&prefix(b'h', &[hash(body)]))? {
+            let eid = last_id(&key);
+            if Some(eid) == except {
+                continue;
+            }
+            let gid = self.find(id(&gid))?;
+            if self.kv().get(&key_of(b'g', &[gid, eid]))?.is_some_and(|stored| stored == body) {
+                return Ok(Some(gid));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Store the expressions of group i, or find the group of a duplicate, merging groups when
+    /// expressions are found in more than one.
+    fn insert_group(&mut self, i: usize, eids: &[usize], exprs: Vec<&RawExpr>, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        let mut gid = i;
+        let mut owned = false; // some expression is already in gid
+        for (j, e) in exprs.iter().enumerate() {
+            let (body, children) = self.body(e, scalars)?;
+
+            match self.lookup(&body, None)? {
+                Some(id) if !owned => {
+                    // the group already exists, forward its id too
+                    if id != gid {
+                        self.kv_mut().put(&key_of(b'f', &[gid]), &(id as u64).to_be_bytes())?;
+                        self.merged = true;
+                    }
+                    gid = id;
+                    owned = true;
+                }
+                Some(id) => {
+                    if id != gid {
+                        gid = self.merge(gid, id)?;
+                    }
+                }
+                None => {
+                    owned = true;
+                    self.store(gid, eids[j], &body, &children)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, gid: usize, eid: usize, body: &[u8], children: &[usize]) -> Result<(), Box<dyn Error>> {
+        let kv = self.kv_mut();
+        kv.put(&key_of(b'g', &[gid, eid]), body)?;
+        kv.put(&key_of(b'h', &[hash(body), eid]), &(gid as u64).to_be_bytes())?;
+        // parent expressions are rewritten if their children are merged
+        for c in children.iter() {
+            kv.put(&key_of(b'p', &[*c, eid]), &(gid as u64).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Current group of a group id, following the forwarding of merged groups.
+    fn find(&self, mut gid: usize) -> Result<usize, Box<dyn Error>> {
+        if self.merged {
+            while let Some(next) = self.kv().get(&key_of(b'f', &[gid]))? {
+                gid = id(&next);
+            }
+        }
+        Ok(gid)
+    }
+
+    /// Merge two groups into the one with the lowest id and rewrite the children of expressions
+    /// with the other as a child, which may find more duplicates and thus more groups to merge.
+    fn merge(&mut self, a: usize, b: usize) -> Result<usize, Box<dyn Error>> {
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.find(a)?, self.find(b)?);
+            if a == b {
+                continue;
+            }
+            let (winner, loser) = (min(a, b), max(a, b));
+            self.merged = true;
+
+            for (key, body) in self.kv().scan(&prefix(b'g', &[loser]))? {
+                let eid = last_id(&key);
+                let kv = self.kv_mut();
+                kv.put(&key_of(b'g', &[winner, eid]), &body)?;
+                kv.delete(&key)?;
+            }
+            self.kv_mut().put(&key_of(b'f', &[loser]), &(winner as u64).to_be_bytes())?;
+
+            for (key, owner) in self.kv().scan(&prefix(b'p', &[loser]))? {
+                self.kv_mut().delete(&key)?;
+                let eid = last_id(&key);
+                let owner = self.find(id(&owner))?;
+
+                let Some(mut body) = self.kv().get(&key_of(b'g', &[owner, eid]))? else {
+                    continue;
+                };
+                let old = hash(&body);
+                let (op, _, mut children) = header(&body);
+                for c in children.iter_mut().filter(|c| **c == loser) {
+                    *c = winner;
+                }
+                if self.canonicalize && op == OP_JOIN {
+                    children.sort();
+                }
+                set_children(&mut body, &children);
+                let kv = self.kv_mut();
+                kv.delete(&key_of(b'h', &[old, eid]))?;
+                kv.put(&key_of(b'g', &[owner, eid]), &body)?;
+                kv.put(&key_of(b'h', &[hash(&body), eid]), &(owner as u64).to_be_bytes())?;
+                kv.put(&key_of(b'p', &[winner, eid]), &(owner as u64).to_be_bytes())?;
+
+                if let Some(other) = self.lookup(&body, Some(eid))? {
+                    if other != owner {
+                        // the parent is now a duplicate of an expression in another group
+                        pending.push((owner, other));
+                    }
+                }
+            }
+        }
+
+        self.find(a)
+    }
+
+    fn optimize_expression(&mut self, info: &mut MatchInfo, expr_id: usize, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        if info.visited_exprs.insert(expr_id) {
+            let (op, _, children) = header(body);
+
+            // explore children first
+            for c in children.iter() {
+                self.explore_group(info, *c)?;
+            }
+
+            // top_matches in optimize_expression task
+            let mut _picks = vec![];
+            if op == OP_FILTER {
+
+                // match_and_pick_expr in apply_rule task
+                for (_, bot_body) in self.kv().scan(&prefix(b'g', &[children[0]]))? {
+                    let (bot_op, _, bot_children) = header(&bot_body);
+                    if bot_op == OP_JOIN {
+                        _picks.push(bot_children);
+
+                        let now = Instant::now();
+                        if info
+                            .hist
+                            .record(now.duration_since(info.last).as_nanos() as u64)
+                            .is_err()
+                        {
+                            warn!("histogram overflow")
+                        }
+                        info.last = now;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn explore_group(&mut self, info: &mut MatchInfo, group_id: usize) -> Result<(), Box<dyn Error>> {
+        if info.visited_groups.insert(group_id) {
+            info.root_groups.insert(group_id);
+            for (key, body) in self.kv().scan(&prefix(b'g', &[group_id]))? {
+                self.optimize_expression(info, last_id(&key), &body)?;
+            }
+        } else if info.root_groups.insert(group_id) {
+            info.shared += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Key of a tag and ids, as t/<id>/<id>.
+fn key_of(tag: u8, ids: &[usize]) -> Vec<u8> {
+    let mut key = vec![tag];
+    for id in ids {
+        key.push(b'/');
+        key.extend_from_slice(&(*id as u64).to_be_bytes());
+    }
+    key
+}
+
+/// Prefix of the keys of a tag and ids, as t/<id>/.
+fn prefix(tag: u8, ids: &[usize]) -> Vec<u8> {
+    let mut key = key_of(tag, ids);
+    key.push(b'/');
+    key
+}
+
+fn id(bytes: &[u8]) -> usize {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap()) as usize
+}
+
+fn last_id(key: &[u8]) -> usize {
+    id(&key[key.len() - 8..])
+}
+
+/// 64-bit FNV-1a, which unlike the standard hasher is stable, as hashes are kept in the store.
+fn hash(body: &[u8]) -> usize {
+    body.iter().fold(0xcbf29ce484222325u64, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3)) as usize
+}
+
+/// Value of an expression: operator, payload and children first, so that children can be
+/// rewritten in place, then extra payload and the predicate tree, all in fixed-width
+/// little-endian fields.
+fn encode(e: &RawExpr, children: &[usize], scalars: &[RawScalar]) -> Vec<u8> {
+    let mut body = vec![e.op as u8];
+    body.extend_from_slice(&(e.data as u64).to_le_bytes());
+    body.push(children.len() as u8);
+    for c in children.iter() {
+        body.extend_from_slice(&(*c as u64).to_le_bytes());
+    }
+    body.extend_from_slice(&(e.extra.len() as u32).to_le_bytes());
+    for v in e.extra.iter() {
+        body.extend_from_slice(&v.to_le_bytes());
+    }
+    match e.pred {
+        Some(pred) => {
+            body.push(1);
+            put_scalar(&mut body, scalars, pred);
+        }
+        None => body.push(0),
+    }
+    body
+}
+
+fn put_scalar(body: &mut Vec<u8>, scalars: &[RawScalar], id: usize) {
+    let s = &scalars[id];
+    body.push(s.op as u8);
+    body.extend_from_slice(&s.value.to_le_bytes());
+    body.push(s.children.len() as u8);
+    for c in s.children.iter() {
+        put_scalar(body, scalars, *c);
+    }
+}
+
+/// Operator, payload and children of an expression.
+fn header(body: &[u8]) -> (usize, u64, Vec<usize>) {
+    let word = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+    let children = (0..body[9] as usize).map(|k| word(10 + 8 * k) as usize).collect();
+    (body[0] as usize, word(1), children)
+}
+
+fn set_children(body: &mut [u8], children: &[usize]) {
+    for (k, c) in children.iter().enumerate() {
+        body[10 + 8 * k..18 + 8 * k].copy_from_slice(&(*c as u64).to_le_bytes());
+    }
+}
+
+#[cfg(feature = "redb")]
+const REDB_TABLE: redb::TableDefinition<&[u8], &[u8]> = redb::TableDefinition::new("memo");
+
+/// redb, with a write transaction for each group, committed durably only if requested.
+#[cfg(feature = "redb")]
+struct RedbKv(redb::Database, Option<redb::WriteTransaction>);
+
+#[cfg(feature = "redb")]
+impl RedbKv {
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let db = redb::Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(REDB_TABLE)?;
+        txn.commit()?;
+        Ok(RedbKv(db, None))
+    }
+
+    fn txn(&self) -> Result<&redb::WriteTransaction, Box<dyn Error>> {
+        Ok(self.1.as_ref().ok_or("write outside of a transaction")?)
+    }
+}
+
+#[cfg(feature = "redb")]
+fn redb_get(table: &impl redb::ReadableTable<&'static [u8], &'static [u8]>, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let value = table.get(key)?.map(|v| v.value().to_vec());
+    Ok(value)
+}
+
+#[cfg(feature = "redb")]
+fn redb_scan(table: &impl redb::ReadableTable<&'static [u8], &'static [u8]>, prefix: &[u8]) -> Result<Entries, Box<dyn Error>> {
+    let mut entries = vec![];
+    for entry in table.range(prefix..)? {
+        let (k, v) = entry?;
+        if !k.value().starts_with(prefix) {
+            break;
+        }
+        entries.push((k.value().to_vec(), v.value().to_vec()));
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "redb")]
+impl Kv for RedbKv {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        // reads in the write transaction see its writes
+        match &self.1 {
+            Some(txn) => redb_get(&txn.open_table(REDB_TABLE)?, key),
+            None => redb_get(&self.0.begin_read()?.open_table(REDB_TABLE)?, key),
+        }
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Entries, Box<dyn Error>> {
+        match &self.1 {
+            Some(txn) => redb_scan(&txn.open_table(REDB_TABLE)?, prefix),
+            None => redb_scan(&self.0.begin_read()?.open_table(REDB_TABLE)?, prefix),
+        }
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        self.1 = Some(self.0.begin_write()?);
+        Ok(())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut table = self.txn()?.open_table(REDB_TABLE)?;
+        table.insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut table = self.txn()?.open_table(REDB_TABLE)?;
+        table.remove(key)?;
+        Ok(())
+    }
+
+    fn commit(&mut self, durable: bool) -> Result<(), Box<dyn Error>> {
+        let mut txn = self.1.take().ok_or("commit outside of a transaction")?;
+        // pages freed by non-durable commits are only reused after a durable one
+        txn.set_durability(if durable { redb::Durability::Immediate } else { redb::Durability::None });
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+/// sled, without transactions, as each write is atomic, which flushes in the background every
+/// 500ms, or on commit if durable.
+#[cfg(feature = "sled")]
+struct SledKv(sled::Db);
+
+#[cfg(feature = "sled")]
+impl Kv for SledKv {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.0.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Entries, Box<dyn Error>> {
+        Ok(self.0.scan_prefix(prefix).map(|entry| entry.map(|(k, v)| (k.to_vec(), v.to_vec()))).collect::<Result<_, _>>()?)
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.0.insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.0.remove(key)?;
+        Ok(())
+    }
+
+    fn commit(&mut self, durable: bool) -> Result<(), Box<dyn Error>> {
+        if durable {
+            self.0.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// RocksDB, without transactions, writing to the WAL without syncing it unless durable.
+#[cfg(feature = "rocksdb")]
+struct RocksKv(rocksdb::DB);
+
+#[cfg(feature = "rocksdb")]
+impl RocksKv {
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        Ok(RocksKv(rocksdb::DB::open(&opts, path)?))
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl Kv for RocksKv {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.0.get(key)?)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Entries, Box<dyn Error>> {
+        let mut entries = vec![];
+        for entry in self.0.iterator(rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward)) {
+            let (k, v) = entry?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            entries.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.0.put(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.0.delete(key)?;
+        Ok(())
+    }
+
+    fn commit(&mut self, durable: bool) -> Result<(), Box<dyn Error>> {
+        if durable {
+            self.0.flush_wal(true)?;
+        }
+        Ok(())
+    }
+}
+
+/// LMDB with heed, opened without syncing commits. Writes are kept in memory until commit and
+/// then written in a single write transaction, as one left open would borrow the environment.
+#[cfg(feature = "lmdb")]
+struct LmdbKv {
+    env: heed::Env,
+    db: heed::Database<heed::types::Bytes, heed::types::Bytes>,
+    batch: std::collections::BTreeMap<Vec<u8>, Option<Vec<u8>>>, // None to delete
+}
+
+#[cfg(feature = "lmdb")]
+impl LmdbKv {
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(path)?;
+        let env = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(1 << 36) // address space only, the file grows as needed
+                .flags(heed::EnvFlags::NO_SYNC)
+                .open(path)?
+        };
+        let mut txn = env.write_txn()?;
+        let db = env.create_database(&mut txn, None)?;
+        txn.commit()?;
+        Ok(LmdbKv { env, db, batch: Default::default() })
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl Drop for LmdbKv {
+    fn drop(&mut self) {
+        // heed caches opened environments by path, which would otherwise hand back this one after the files are removed
+        let _ = self.env.clone().prepare_for_closing();
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl Kv for LmdbKv {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if let Some(value) = self.batch.get(key) {
+            return Ok(value.clone());
+        }
+        let txn = self.env.read_txn()?;
+        let value = self.db.get(&txn, key)?.map(|v| v.to_vec());
+        Ok(value)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Result<Entries, Box<dyn Error>> {
+        let txn = self.env.read_txn()?;
+        let mut entries = self.db.prefix_iter(&txn, prefix)?
+            .map(|entry| entry.map(|(k, v)| (k.to_vec(), v.to_vec())))
+            .collect::<Result<std::collections::BTreeMap<_, _>, _>>()?;
+        for (k, v) in self.batch.range(prefix.to_vec()..).take_while(|(k, _)| k.starts_with(prefix)) {
+            match v {
+                Some(v) => entries.insert(k.clone(), v.clone()),
+                None => entries.remove(k),
+            };
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.batch.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.batch.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn commit(&mut self, durable: bool) -> Result<(), Box<dyn Error>> {
+        let mut txn = self.env.write_txn()?;
+        for (key, value) in std::mem::take(&mut self.batch) {
+            match value {
+                Some(value) => self.db.put(&mut txn, &key, &value)?,
+                None => {
+                    self.db.delete(&mut txn, &key)?;
+                }
+            }
+        }
+        txn.commit()?;
+        if durable {
+            self.env.force_sync()?;
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,10 @@
-use crate::generator::RawMemo;
+use crate::generator::{Generator, RawMemo};
 use crate::Benchmark;
 use hdrhistogram::Histogram;
 use log::warn;
 use rand_chacha::ChaCha8Rng;
 use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct BenchNull {}
 
@@ -24,6 +24,22 @@ impl Benchmark for BenchNull {
         Ok(hist)
     }
 
+    fn add_stream(&mut self, groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        // only generate, to measure the generator itself
+        let mut start = Instant::now();
+        for _ in groups {
+            if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
+                warn!("histogram overflow")
+            }
+            start = Instant::now();
+        }
+
+        Ok(hist)
+    }
+
     fn retrieve(&mut self, _: ChaCha8Rng, _: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
         let hist =
             Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
@@ -41,4 +57,13 @@ impl Benchmark for BenchNull {
 
         Ok(hist)
     }
+
+    fn winners(&mut self, _: ChaCha8Rng, _: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        warn!("no benchmark selected");
+
+        Ok(hist)
+    }
 }
@@ -1,17 +1,22 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use hdrhistogram::Histogram;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use optd_db::cascades::expressions::{LogicalExpression, LogicalExpressionId};
 use optd_db::cascades::groups::{RelationalGroupId, ScalarGroupId};
 use optd_db::cascades::memo::Memoize;
 use optd_db::operators::relational::logical::filter::Filter;
 use optd_db::operators::relational::logical::join::Join;
 use optd_db::operators::relational::logical::scan::Scan;
+use optd_db::operators::scalar::and::And;
+use optd_db::operators::scalar::column_ref::ColumnRef;
 use optd_db::operators::scalar::constants::Constant;
+use optd_db::operators::scalar::equal::Equal;
+use optd_db::operators::scalar::less_than::LessThan;
+use optd_db::operators::scalar::or::Or;
 use optd_db::operators::scalar::ScalarOperator;
 use optd_db::storage::memo::SqliteMemo;
 use optd_db::values::OptdValue;
@@ -19,19 +24,21 @@ use rand::Rng;
 use rand_chacha::ChaCha8Rng;
 use tokio::runtime::Runtime;
 use crate::Benchmark;
-use crate::generator::RawMemo;
+use crate::generator::{Generator, RawExpr, RawMemo, RawScalar, SCALAR_AND, SCALAR_COLUMN, SCALAR_CONST, SCALAR_EQ, SCALAR_LT, SCALAR_OR};
 
 pub struct BenchOptdDb {
     memo: SqliteMemo,
     group_ids: Vec<RelationalGroupId>,
-    entry: RelationalGroupId,
+    entries: Vec<RelationalGroupId>,
+    pred_vals: HashMap<ScalarGroupId, usize>, // unique predicate roots, when scalar trees are used
+    canonicalize: bool,
 }
 
 impl BenchOptdDb {
-    pub fn new(database: &str) -> Result<Self,Box<dyn Error>> {
+    pub fn new(database: &str, canonicalize: bool) -> Result<Self,Box<dyn Error>> {
         let runtime = Runtime::new().unwrap();
         runtime.block_on(async {
-            Ok(BenchOptdDb { memo: SqliteMemo::new(database).await?, group_ids: vec![], entry: RelationalGroupId(0) })
+            Ok(BenchOptdDb { memo: SqliteMemo::new(database).await?, group_ids: vec![], entries: vec![], pred_vals: HashMap::new(), canonicalize })
         })
     }
 }
@@ -41,49 +48,20 @@ impl Benchmark for BenchOptdDb {
         let mut hist =
             Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
 
+        if memo.exprs.iter().any(|e| !e.extra.is_empty()) {
+            warn!("extra payload not supported, ignored");
+        }
+
         let runtime = Runtime::new().unwrap();
         runtime.block_on(async {
             for g in memo.groups.iter() {
                 let start = Instant::now();
-                let mut group_id = None;
-
-                for j in g.exprs.iter() {
-                    let e = &memo.exprs[*j];
-
-                    let expr = match e.op {
-                        0 => LogicalExpression::Scan(Scan {
-                            table_name: OptdValue::String((*j).to_string()),
-                            predicate: self.predicate_from_val(*j).await,
-                        }),
-                        1 => LogicalExpression::Filter(Filter {
-                            predicate: self.predicate_from_val(*j).await,
-                            child: self.group_ids[e.children[0]],
-                        }),
-                        2 => LogicalExpression::Join(Join {
-                            join_type: OptdValue::Int64(0),
-                            left: self.group_ids[e.children[0]],
-                            right: self.group_ids[e.children[1]],
-                            condition: self.predicate_from_val(*j).await,
-                        }),
-                        _ => unreachable!(),
-                    };
-
-                    match group_id {
-                        None => {
-                            // first expression in group, create group
-                            let e = self.memo.add_logical_expr(&expr).await?;
-                            group_id = Some(e);
-                        }
-                        Some(id) => {
-                            // add expression to existing group
-                            self.memo.add_logical_expr_to_group(&expr, id).await?;
-                        }
-                    };
-                }
+
+                let group_id = self.add_group(g.exprs.iter().map(|j| &memo.exprs[*j]).collect(), &memo.scalars).await?;
                 if g.id >= self.group_ids.len() {
-                    self.group_ids.push(group_id.unwrap());
+                    self.group_ids.push(group_id);
                 } else {
-                    self.group_ids[g.id] = group_id.unwrap();
+                    self.group_ids[g.id] = group_id;
                 }
 
                 if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
@@ -91,7 +69,30 @@ impl Benchmark for BenchOptdDb {
                 }
             }
 
-            self.entry = self.group_ids[memo.entry];
+            self.entries = memo.entries.iter().map(|e| self.group_ids[*e]).collect();
+
+            Ok(hist)
+        })
+    }
+
+    fn add_stream(&mut self, groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            for g in groups.by_ref() {
+                let start = Instant::now();
+
+                let group_id = self.add_group(g.exprs.iter().map(|(_, e)| e).collect(), &vec![]).await?;
+                self.group_ids.push(group_id);
+
+                if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
+                    warn!("histogram overflow")
+                }
+            }
+
+            self.entries = groups.entries().iter().map(|e| self.group_ids[*e]).collect();
 
             Ok(hist)
         })
@@ -132,7 +133,8 @@ impl Benchmark for BenchOptdDb {
                 }
 
                 ids.sort();
-                assert_eq!(ids, memo.groups[g].exprs, "incorrect memo (do not use --shuffle merge?)")
+                ids.dedup();
+                assert_eq!(ids, memo.expected(g), "incorrect memo (do not use --shuffle merge?)")
             }
 
             Ok(hist)
@@ -143,34 +145,140 @@ impl Benchmark for BenchOptdDb {
         let mut info = MatchInfo {
             visited_exprs: Default::default(),
             visited_groups: Default::default(),
+            root_groups: Default::default(),
+            shared: 0,
             hist: Histogram::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?,
             last: Instant::now(),
         };
 
         let runtime = Runtime::new().unwrap();
         runtime.block_on(async {
-            self.explore_group(&mut info, self.entry).await;
+            for root in self.entries.clone() {
+                info.root_groups.clear();
+                self.explore_group(&mut info, root).await;
+            }
+
+            info!("match: {} roots, {} groups explored, {} shared with previous roots",
+                self.entries.len(), info.visited_groups.len(), info.shared);
 
             Ok(info.hist)
         })
     }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut ids = vec![];
+            for (_,expr) in self.memo.get_all_logical_exprs_in_group(self.group_ids[g]).await? {
+                match expr.deref() {
+                    LogicalExpression::Scan(expr) => ids.push(self.val_from_predicate(expr.predicate).await),
+                    LogicalExpression::Filter(expr) => ids.push(self.val_from_predicate(expr.predicate).await),
+                    LogicalExpression::Join(expr) => ids.push(self.val_from_predicate(expr.condition).await),
+                    _ => {}
+                }
+            }
+            Ok(ids)
+        })
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.group_ids[g] == self.group_ids[h])
+    }
 }
 
 struct MatchInfo {
     visited_exprs: HashSet<LogicalExpressionId>,
     visited_groups: HashSet<RelationalGroupId>,
+    root_groups: HashSet<RelationalGroupId>, // reached from the current root
+    shared: usize, // groups already explored from previous roots
     hist: Histogram<u64>,
     last: Instant,
 }
 
 impl BenchOptdDb {
+    async fn add_group(&mut self, exprs: Vec<&RawExpr>, scalars: &Vec<RawScalar>) -> Result<RelationalGroupId, Box<dyn Error>> {
+        let mut group_id = None;
+
+        for e in exprs {
+            let predicate = match e.pred {
+                None => self.predicate_from_val(e.data).await,
+                Some(pred) => {
+                    // scalar tree made unique by a constant, all in scalar groups
+                    let tree = self.predicate_from_tree(scalars, pred).await;
+                    let unique = self.predicate_from_val(e.data).await;
+                    let root = self.memo.add_scalar_expr(
+                        &ScalarOperator::And(And::new(tree, unique))).await?;
+                    self.pred_vals.insert(root, e.data);
+                    root
+                }
+            };
+
+            let children = match self.canonicalize {
+                true => e.canonical_children(),
+                false => e.children.clone(),
+            };
+            let expr = match e.op {
+                0 => LogicalExpression::Scan(Scan {
+                    table_name: OptdValue::String(e.data.to_string()),
+                    predicate,
+                }),
+                1 => LogicalExpression::Filter(Filter {
+                    predicate,
+                    child: self.group_ids[children[0]],
+                }),
+                2 => LogicalExpression::Join(Join {
+                    join_type: OptdValue::Int64(0),
+                    left: self.group_ids[children[0]],
+                    right: self.group_ids[children[1]],
+                    condition: predicate,
+                }),
+                op => return Err(format!("operator {} not supported", op).into()),
+            };
+
+            match group_id {
+                None => {
+                    // first expression in group, create group
+                    let e = self.memo.add_logical_expr(&expr).await?;
+                    group_id = Some(e);
+                }
+                Some(id) => {
+                    // add expression to existing group
+                    self.memo.add_logical_expr_to_group(&expr, id).await?;
+                }
+            };
+        }
+
+        Ok(group_id.unwrap())
+    }
+
     async fn predicate_from_val(&self, value: usize) -> ScalarGroupId {
         self.memo.add_scalar_expr(
             &ScalarOperator::Constant(
                 Constant::new(OptdValue::Int64(value as i64)))).await.unwrap()
     }
 
+    async fn predicate_from_tree(&self, scalars: &Vec<RawScalar>, id: usize) -> ScalarGroupId {
+        let s = &scalars[id];
+        let mut children = vec![];
+        for c in s.children.iter() {
+            children.push(Box::pin(self.predicate_from_tree(scalars, *c)).await);
+        }
+        let op = match s.op {
+            SCALAR_CONST => ScalarOperator::Constant(Constant::new(OptdValue::Int64(s.value as i64))),
+            SCALAR_COLUMN => ScalarOperator::ColumnRef(ColumnRef::new(s.value as i64)),
+            SCALAR_EQ => ScalarOperator::Equal(Equal::new(children[0], children[1])),
+            SCALAR_LT => ScalarOperator::LessThan(LessThan::new(children[0], children[1])),
+            SCALAR_AND => ScalarOperator::And(And::new(children[0], children[1])),
+            SCALAR_OR => ScalarOperator::Or(Or::new(children[0], children[1])),
+            _ => unreachable!(),
+        };
+        self.memo.add_scalar_expr(&op).await.unwrap()
+    }
+
     async fn val_from_predicate(&self, sid: ScalarGroupId) -> usize {
+        if let Some(v) = self.pred_vals.get(&sid) {
+            return *v;
+        }
         if let ScalarOperator::Constant(c) = &self.memo.get_all_scalar_exprs_in_group(sid).await.unwrap()[0].1.deref() {
             if let OptdValue::Int64(v) = c.value {
                 v as usize
@@ -184,10 +292,13 @@ impl BenchOptdDb {
 
     async fn explore_group(&mut self, info: &mut MatchInfo, group_id: RelationalGroupId) {
         if info.visited_groups.insert(group_id) {
+            info.root_groups.insert(group_id);
             let exprs = self.memo.get_all_logical_exprs_in_group(group_id).await.unwrap();
             for (id,expr) in exprs {
                 Box::pin(self.optimize_expression(info, id, expr)).await;
             }
+        } else if info.root_groups.insert(group_id) {
+            info.shared += 1;
         }
     }
 
@@ -1,4 +1,4 @@
-use crate::generator::RawMemo;
+use crate::generator::{RawMemo, RawScalar, OP_AGGREGATE, OP_PROJECT};
 use crate::Benchmark;
 use hdrhistogram::Histogram;
 use log::warn;
@@ -14,15 +14,17 @@ use tokio::runtime::Runtime;
 pub struct BenchOptdMem {
     memo: MemoryMemo,
     group_ids: Vec<GroupId>,
-    entry: GroupId,
+    entries: Vec<GroupId>,
+    canonicalize: bool,
 }
 
 impl BenchOptdMem {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new(canonicalize: bool) -> Result<Self, Box<dyn Error>> {
         Ok(BenchOptdMem {
             memo: MemoryMemo::default(),
             group_ids: Vec::new(),
-            entry: GroupId(0),
+            entries: Vec::new(),
+            canonicalize,
         })
     }
 }
@@ -41,10 +43,23 @@ impl Benchmark for BenchOptdMem {
                 for j in g.exprs.iter() {
                     let e = &memo.exprs[*j];
 
+                    let mut data = vec![OperatorData::Int64(e.data as i64)];
+                    data.extend(e.extra.iter().map(|v| OperatorData::Int64(*v as i64)));
+                    if let Some(pred) = e.pred {
+                        // scalars are stored inline, flattened in prefix order
+                        flatten_scalar(&memo.scalars, pred, &mut data);
+                    }
+
+                    let children = match self.canonicalize {
+                        true => e.canonical_children(),
+                        false => e.children.clone(),
+                    };
                     let expr = match e.op {
-                        0 => LogicalExpression { tag: "Scan".to_string(), data: vec![OperatorData::Int64(*j as i64)], children: vec![] },
-                        1 => LogicalExpression { tag: "Filter".to_string(), data: vec![OperatorData::Int64(*j as i64)], children: vec![Child::Singleton(self.group_ids[e.children[0]])] },
-                        2 => LogicalExpression { tag: "Filter".to_string(), data: vec![OperatorData::Int64(*j as i64)], children: vec![Child::Singleton(self.group_ids[e.children[0]]), Child::Singleton(self.group_ids[e.children[1]])] },
+                        0 => LogicalExpression { tag: "Scan".to_string(), data, children: vec![] },
+                        1 => LogicalExpression { tag: "Filter".to_string(), data, children: vec![Child::Singleton(self.group_ids[children[0]])] },
+                        2 => LogicalExpression { tag: "Filter".to_string(), data, children: vec![Child::Singleton(self.group_ids[children[0]]), Child::Singleton(self.group_ids[children[1]])] },
+                        OP_PROJECT => LogicalExpression { tag: "Project".to_string(), data, children: vec![Child::Singleton(self.group_ids[children[0]])] },
+                        OP_AGGREGATE => LogicalExpression { tag: "Aggregate".to_string(), data, children: vec![Child::Singleton(self.group_ids[children[0]])] },
                         _ => unreachable!(),
                     };
 
@@ -77,7 +92,7 @@ impl Benchmark for BenchOptdMem {
                 }
             }
 
-            self.entry = self.group_ids[memo.entry];
+            self.entries = memo.entries.iter().map(|e| self.group_ids[*e]).collect();
 
             Ok(hist)
         })
@@ -111,7 +126,8 @@ impl Benchmark for BenchOptdMem {
                 }
 
                 ids.sort();
-                assert_eq!(ids, memo.groups[g].exprs, "incorrect memo")
+                ids.dedup();
+                assert_eq!(ids, memo.expected(g), "incorrect memo")
             }
 
             Ok(hist)
@@ -126,4 +142,31 @@ impl Benchmark for BenchOptdMem {
 
         Ok(hist)
     }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut ids = vec![];
+            for eid in self.memo.get_all_logical_exprs(self.group_ids[g]).await.unwrap() {
+                let expr = self.memo.materialize_logical_expr(eid).await.unwrap();
+                if let OperatorData::Int64(v) = expr.data[0] {
+                    ids.push(v as usize);
+                }
+            }
+            Ok(ids)
+        })
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.group_ids[g] == self.group_ids[h])
+    }
+}
+
+fn flatten_scalar(scalars: &Vec<RawScalar>, id: usize, data: &mut Vec<OperatorData>) {
+    let s = &scalars[id];
+    data.push(OperatorData::Int64(s.op as i64));
+    data.push(OperatorData::Int64(s.value as i64));
+    for c in s.children.iter() {
+        flatten_scalar(scalars, *c, data);
+    }
 }
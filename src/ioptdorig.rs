@@ -1,9 +1,9 @@
-use crate::generator::RawMemo;
+use crate::generator::{RawMemo, OP_AGGREGATE, OP_PROJECT, SCALAR_AND, SCALAR_COLUMN, SCALAR_CONST, SCALAR_EQ, SCALAR_LT, SCALAR_OR};
 use crate::Benchmark;
 use hdrhistogram::Histogram;
-use log::warn;
+use log::{info, warn};
 use optd_original::cascades::{ExprId, GroupId, Memo, NaiveMemo};
-use optd_original::nodes::{PlanNode, ArcPlanNode, NodeType, PlanNodeOrGroup, PredNode, Value};
+use optd_original::nodes::{PlanNode, ArcPlanNode, ArcPredNode, NodeType, PlanNodeOrGroup, PredNode, Value};
 use rand::Rng;
 use rand_chacha::ChaCha8Rng;
 use std::collections::HashSet;
@@ -18,11 +18,19 @@ pub enum BenchRelNodeTyp {
     Scan,
     Filter,
     Join,
+    Project,
+    Aggregate,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum BenchPredTyp {
-    Data
+    Data,
+    Const,
+    Column,
+    Eq,
+    Lt,
+    And,
+    Or,
 }
 
 impl NodeType for BenchRelNodeTyp {
@@ -31,6 +39,8 @@ impl NodeType for BenchRelNodeTyp {
             BenchRelNodeTyp::Scan => true,
             BenchRelNodeTyp::Filter => true,
             BenchRelNodeTyp::Join => true,
+            BenchRelNodeTyp::Project => true,
+            BenchRelNodeTyp::Aggregate => true,
         }
     }
 
@@ -46,6 +56,8 @@ impl Display for BenchRelNodeTyp {
                 BenchRelNodeTyp::Scan => "Scan",
                 BenchRelNodeTyp::Filter => "Filter",
                 BenchRelNodeTyp::Join => "Join",
+                BenchRelNodeTyp::Project => "Project",
+                BenchRelNodeTyp::Aggregate => "Aggregate",
             }
         )
     }
@@ -53,22 +65,27 @@ impl Display for BenchRelNodeTyp {
 
 impl Display for BenchPredTyp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "()")
+        match self {
+            Data => write!(f, "()"),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
 pub struct BenchOptdOriginal {
     memo: NaiveMemo<BenchRelNodeTyp>,
     group_ids: Vec<GroupId>, // because get_all_group_ids() is pub(crate)
-    entry: usize,
+    entries: Vec<usize>,
+    canonicalize: bool,
 }
 
 impl BenchOptdOriginal {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new(canonicalize: bool) -> Result<Self, Box<dyn Error>> {
         Ok(BenchOptdOriginal {
             memo: NaiveMemo::new(Arc::new([])),
             group_ids: vec![],
-            entry: 0,
+            entries: vec![],
+            canonicalize,
         })
     }
 }
@@ -85,39 +102,54 @@ impl Benchmark for BenchOptdOriginal {
             for j in g.exprs.iter() {
                 let e = &memo.exprs[*j];
 
+                let raw_children = match self.canonicalize {
+                    true => e.canonical_children(),
+                    false => e.children.clone(),
+                };
                 let mut children = vec![];
-                for c in e.children.iter() {
+                for c in raw_children.iter() {
                     children.push(PlanNodeOrGroup::Group(self.group_ids[*c]));
                 }
 
                 // build expressions with unique predicates
+                let mut predicates = vec![ Arc::new(PredNode{
+                    typ: Data,
+                    children: e.extra.iter().map(|v| Arc::new(PredNode {
+                        typ: Data,
+                        children: vec![],
+                        data: Some(Value::UInt64(*v)),
+                    })).collect(),
+                    data: Some(Value::UInt64(e.data as u64)),
+                }) ];
+                if let Some(pred) = e.pred {
+                    // scalar tree, memoized by the memo itself
+                    predicates.push(Self::pred_tree(memo, pred));
+                }
                 let expr = ArcPlanNode::new(match e.op {
                     0 => PlanNode {
                         typ: BenchRelNodeTyp::Scan,
                         children: children,
-                        predicates: vec![ Arc::new(PredNode{
-                            typ: Data,
-                            children: vec![],
-                            data: Some(Value::UInt64(*j as u64)),
-                        }) ],
+                        predicates: predicates,
                     },
                     1 => PlanNode {
                         typ: BenchRelNodeTyp::Filter,
                         children: children,
-                        predicates: vec![ Arc::new(PredNode{
-                            typ: Data,
-                            children: vec![],
-                            data: Some(Value::UInt64(*j as u64)),
-                        }) ],
+                        predicates: predicates,
                     },
                     2 => PlanNode {
                         typ: BenchRelNodeTyp::Join,
                         children: children,
-                        predicates: vec![ Arc::new(PredNode{
-                            typ: Data,
-                            children: vec![],
-                            data: Some(Value::UInt64(*j as u64)),
-                        }) ],
+                        predicates: predicates,
+                    },
+                    OP_PROJECT => PlanNode {
+                        typ: BenchRelNodeTyp::Project,
+                        children: children,
+                        predicates: predicates,
+                    },
+                    OP_AGGREGATE => PlanNode {
+                        typ: BenchRelNodeTyp::Aggregate,
+                        children: children,
+                        predicates: predicates,
                     },
                     _ => unreachable!(),
                 });
@@ -148,7 +180,7 @@ impl Benchmark for BenchOptdOriginal {
             }
         }
 
-        self.entry = memo.entry;
+        self.entries = memo.entries.clone();
 
         Ok(hist)
     }
@@ -179,7 +211,8 @@ impl Benchmark for BenchOptdOriginal {
             }
 
             ids.sort();
-            assert_eq!(ids, memo.groups[g].exprs, "incorrect memo")
+            ids.dedup();
+            assert_eq!(ids, memo.expected(g), "incorrect memo")
         }
 
         Ok(hist)
@@ -189,23 +222,66 @@ impl Benchmark for BenchOptdOriginal {
         let mut info = MatchInfo {
             visited_exprs: Default::default(),
             visited_groups: Default::default(),
+            root_groups: Default::default(),
+            shared: 0,
             hist: Histogram::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?,
             last: Instant::now(),
         };
-        self.explore_group(&mut info, self.group_ids[self.entry]);
+        for root in self.entries.clone() {
+            info.root_groups.clear();
+            self.explore_group(&mut info, self.group_ids[root]);
+        }
+
+        info!("match: {} roots, {} groups explored, {} shared with previous roots",
+            self.entries.len(), info.visited_groups.len(), info.shared);
 
         Ok(info.hist)
     }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut ids = vec![];
+        for e in self.memo.get_all_exprs_in_group(self.group_ids[g]) {
+            let expr = self.memo.get_expr_memoed(e);
+            if let Some(Value::UInt64(v)) = self.memo.get_pred(expr.predicates[0]).data {
+                ids.push(v as usize);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.group_ids[g] == self.group_ids[h])
+    }
 }
 
 struct MatchInfo {
     visited_exprs: HashSet<ExprId>,
     visited_groups: HashSet<GroupId>,
+    root_groups: HashSet<GroupId>, // reached from the current root
+    shared: usize, // groups already explored from previous roots
     hist: Histogram<u64>,
     last: Instant,
 }
 
 impl BenchOptdOriginal {
+    fn pred_tree(memo: &RawMemo, id: usize) -> ArcPredNode<BenchRelNodeTyp> {
+        let s = &memo.scalars[id];
+        let (typ, data) = match s.op {
+            SCALAR_CONST => (BenchPredTyp::Const, Some(Value::UInt64(s.value))),
+            SCALAR_COLUMN => (BenchPredTyp::Column, Some(Value::UInt64(s.value))),
+            SCALAR_EQ => (BenchPredTyp::Eq, None),
+            SCALAR_LT => (BenchPredTyp::Lt, None),
+            SCALAR_AND => (BenchPredTyp::And, None),
+            SCALAR_OR => (BenchPredTyp::Or, None),
+            _ => unreachable!(),
+        };
+        Arc::new(PredNode {
+            typ,
+            children: s.children.iter().map(|c| Self::pred_tree(memo, *c)).collect(),
+            data,
+        })
+    }
+
     fn optimize_expression(&mut self, info: &mut MatchInfo, expr_id: ExprId) {
         if info.visited_exprs.insert(expr_id) {
             let top_expr = self.memo.get_expr_memoed(expr_id);
@@ -245,10 +321,13 @@ impl BenchOptdOriginal {
 
     fn explore_group(&mut self, info: &mut MatchInfo, group_id: GroupId) {
         if info.visited_groups.insert(group_id) {
+            info.root_groups.insert(group_id);
             let exprs = self.memo.get_all_exprs_in_group(group_id);
             for expr in exprs {
                 self.optimize_expression(info, expr);
             }
+        } else if info.root_groups.insert(group_id) {
+            info.shared += 1;
         }
     }
 }
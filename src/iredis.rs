@@ -1,79 +1,331 @@
-use crate::generator::RawMemo;
-use crate::Benchmark;
+use crate::generator::{Generator, RawExpr, RawMemo, RawScalar, OP_JOIN};
+use crate::proxy::Network;
+use crate::{log_summary, Benchmark};
+use clap::ValueEnum;
 use hdrhistogram::Histogram;
-use log::warn;
+use log::{info, warn};
 use rand::Rng;
 use rand_chacha::ChaCha8Rng;
 use redis;
-use serde_json::{from_str, json, Value};
+use redis::{ConnectionLike, IntoConnectionInfo, RedisResult};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, json};
+use std::cmp::{max, min};
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum MatchMode { Client, Server, Both }
+
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum InsertMode { PerCommand, Pipeline, Transaction, Script }
+
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum Encoding { Json, Msgpack, Bincode, CompactBinary }
+
+// Insert the expressions of a group, given as JSON in KEYS, with ids and the group id in ARGV,
+// as insert_group does, and return the resulting group id, whether it was forwarded to an
+// existing group, and the other groups found with duplicates, which are to be merged.
+pub(crate) const INSERT_SCRIPT: &str = r#"
+local function find(g)
+    local next = redis.call('HGET', 'fwd', g)
+    while next do
+        g = next
+        next = redis.call('HGET', 'fwd', g)
+    end
+    return g
+end
+local gid = ARGV[#ARGV]
+local owned, forwarded = false, 0
+local others, fresh = {}, {}
+for j, key in ipairs(KEYS) do
+    local id = redis.call('SET', key, gid, 'NX', 'GET')
+    if id then
+        id = find(id)
+        if not owned then
+            if id ~= gid then
+                redis.call('HSET', 'fwd', gid, id)
+                forwarded = 1
+            end
+            gid = id
+            owned = true
+        elseif id ~= gid then
+            table.insert(others, id)
+        end
+    else
+        owned = true
+        table.insert(fresh, ARGV[j])
+        table.insert(fresh, key)
+        for _, c in ipairs(cjson.decode(key).children) do
+            redis.call('SADD', 'parents:' .. tostring(c), key)
+        end
+    end
+end
+if #fresh > 0 then
+    redis.call('HSET', gid, unpack(fresh))
+end
+return {tonumber(gid), forwarded, others}
+"#;
+
+// Explore all groups reachable from the roots in ARGV, as the client does, and return the
+// number of groups explored, how many were shared with previous roots, and the bindings of
+// Filter over Join as pairs of expression ids.
+pub(crate) const MATCH_SCRIPT: &str = r#"
+local visited = {}
+local explored, shared = 0, 0
+local bindings = {}
+for r = 1, #ARGV do
+    local root_groups = {}
+    local stack = {ARGV[r]}
+    while #stack > 0 do
+        local g = table.remove(stack)
+        if not visited[g] then
+            visited[g] = true
+            root_groups[g] = true
+            explored = explored + 1
+            local exprs = redis.call('HGETALL', g)
+            for i = 1, #exprs, 2 do
+                local e = cjson.decode(exprs[i + 1])
+                for _, c in ipairs(e.children) do
+                    table.insert(stack, tostring(c))
+                end
+                if e.type == 1 then
+                    local bot = redis.call('HGETALL', tostring(e.children[1]))
+                    for j = 1, #bot, 2 do
+                        if cjson.decode(bot[j + 1]).type == 2 then
+                            table.insert(bindings, exprs[i])
+                            table.insert(bindings, bot[j])
+                        end
+                    end
+                end
+            end
+        elseif not root_groups[g] then
+            root_groups[g] = true
+            shared = shared + 1
+        end
+    end
+end
+return {explored, shared, bindings}
+"#;
+
 pub struct BenchRedis {
-    client: redis::Client,
+    pool: Pool,
     ngroups: usize,
-    entry: usize,
+    entries: Vec<usize>,
+    canonicalize: bool,
+    merged: bool, // groups have been merged, so ids need to be forwarded
+    match_mode: MatchMode,
+    insert_mode: InsertMode,
+    encoding: Encoding,
+    encoded: (usize, usize), // expressions and bytes
+}
+
+/// Connections opened once and used in turn, one for each command, pipeline or transaction, which
+/// are counted as round trips to the server. Handles to the pool can be used while borrowing self.
+#[derive(Clone)]
+struct Pool(Rc<RefCell<(Vec<redis::Connection>, usize, usize)>>); // connections, next and round trips
+
+impl Pool {
+    fn trips(&self) -> usize {
+        self.0.borrow().2
+    }
+
+    fn reset(&self) {
+        self.0.borrow_mut().2 = 0;
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut redis::Connection) -> T) -> T {
+        let mut pool = self.0.borrow_mut();
+        let (conns, next, trips) = &mut *pool;
+        let i = *next;
+        *next = (i + 1) % conns.len();
+        *trips += 1;
+        f(&mut conns[i])
+    }
+}
+
+impl ConnectionLike for Pool {
+    fn req_command(&mut self, cmd: &redis::Cmd) -> RedisResult<redis::Value> {
+        self.with(|con| con.req_command(cmd))
+    }
+
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<redis::Value> {
+        self.with(|con| con.req_packed_command(cmd))
+    }
+
+    fn req_packed_commands(&mut self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<redis::Value>> {
+        self.with(|con| con.req_packed_commands(cmd, offset, count))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.0.borrow().0[0].get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.0.borrow_mut().0.iter_mut().all(|con| con.check_connection())
+    }
+
+    fn is_open(&self) -> bool {
+        self.0.borrow().0.iter().all(|con| con.is_open())
+    }
 }
 
 impl BenchRedis {
-    pub fn new(database: String) -> Result<Self, Box<dyn Error>> {
+    pub fn new(database: String, canonicalize: bool, match_mode: MatchMode, insert_mode: InsertMode, encoding: Encoding, connections: usize) -> Result<Self, Box<dyn Error>> {
+        if encoding != Encoding::Json && (match_mode != MatchMode::Client || insert_mode == InsertMode::Script) {
+            return Err("scripts only decode JSON expressions (use --encoding json)".into());
+        }
+        if connections == 0 {
+            return Err("at least one connection is needed".into());
+        }
+
+        // connection setup is measured apart from the workloads, which reuse the connections
+        let client = redis::Client::open(database)?;
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+        let now = Instant::now();
+        let mut conns = vec![];
+        for _ in 0..connections {
+            let start = Instant::now();
+            conns.push(client.get_connection()?);
+            if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
+                warn!("histogram overflow")
+            }
+        }
+        log_summary(hist, "connect", now.elapsed(), false);
+        let mut pool = Pool(Rc::new(RefCell::new((conns, 0, 0))));
+
+        // a memo left by a previous run may have merged groups
+        let merged = redis::cmd("EXISTS").arg("fwd").query(&mut pool)?;
         Ok(BenchRedis {
-            client: redis::Client::open(database)?,
+            pool,
             ngroups: 0,
-            entry: 0,
+            entries: vec![],
+            canonicalize,
+            merged,
+            match_mode,
+            insert_mode,
+            encoding,
+            encoded: (0, 0),
         })
     }
 }
 
+/// URL to connect to a server through a proxy that injects network latency and limits bandwidth.
+pub fn proxied(database: &str, network: &Network) -> Result<String, Box<dyn Error>> {
+    // TLS would be forwarded, but the certificate would not match the address of the proxy
+    let Some(rest) = database.strip_prefix("redis://") else {
+        return Err("the proxy only forwards plain TCP connections, with a redis:// URL".into());
+    };
+    let info = database.into_connection_info()?;
+    let redis::ConnectionAddr::Tcp(host, port) = &info.addr else {
+        return Err("the proxy only forwards TCP connections".into());
+    };
+    // an IPv6 host needs brackets, both to connect and in the URL
+    let target = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, *port).to_string(),
+        Err(_) => format!("{}:{}", host, port),
+    };
+    let addr = crate::proxy::start(target, network)?;
+
+    // only the host and port change, keeping credentials, database and options as given
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let credentials = authority.rsplit_once('@').map_or(String::new(), |(c, _)| format!("{}@", c));
+    Ok(format!("redis://{}{}{}", credentials, addr, path))
+}
+
 impl Benchmark for BenchRedis {
     fn add(&mut self, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
         let mut hist =
             Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
 
-        let mut con = self.client.get_connection()?;
+        let mut con = self.pool.clone();
 
         redis::cmd("FLUSHDB").exec(&mut con)?;
+        self.merged = false;
+        self.encoded = (0, 0);
+        con.reset();
 
         for (i, g) in memo.groups.iter().enumerate() {
             let start = Instant::now();
 
-            let exprs: Vec<String> = g.exprs.iter().map(|j| {
-                let e = &memo.exprs[*j];
-                json!({
-                    "type": e.children.len(),
-                    "children": e.children,
-                    "moredata": *j as u64,
-                }).to_string()
-            }).collect();
+            let exprs: Vec<&RawExpr> = g.exprs.iter().map(|j| &memo.exprs[*j]).collect();
+            self.insert_group(&mut con, i, &g.exprs, exprs, &memo.scalars)?;
+
+            // physical expressions are kept by the group id of the raw memo
+            if !g.phys.is_empty() {
+                let mut cmd = redis::cmd("HSET");
+                cmd.arg(format!("phys:{}", g.id));
+                for pid in g.phys.iter() {
+                    let p = &memo.phys[*pid];
+                    cmd.arg(pid.to_string()).arg(json!({
+                        "type": p.op,
+                        "expr": p.expr,
+                        "children": p.children,
+                        "prop": p.prop,
+                        "cost": p.cost,
+                    }).to_string());
+                }
+                cmd.exec(&mut con)?;
 
-            let mut gid = i.to_string();
-            for e in exprs.iter() {
-                let mut cmd = redis::cmd("SET");
-                cmd.arg(e)
-                    .arg(gid.clone())
-                    .arg("NX")
-                    .arg("GET");
-                let result: redis::Value = cmd.query(&mut con)?;
-                if let Ok(id) = redis::from_redis_value::<String>(&result) {
-                    gid = id;
+                let mut cmd = redis::cmd("HSET");
+                cmd.arg(format!("win:{}", g.id));
+                for (prop, pid) in g.winners.iter() {
+                    cmd.arg(prop.to_string()).arg(pid.to_string());
                 }
+                cmd.exec(&mut con)?;
             }
 
-            let mut cmd = redis::cmd("HSET");
-            cmd.arg(gid);
-            for (j,eid) in g.exprs.iter().enumerate() {
-                cmd.arg(eid.to_string()).arg(exprs[j].clone());
+            if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
+                warn!("histogram overflow")
             }
-            cmd.exec(&mut con)?;
+        }
+
+        info!("redis: {} round trips, {:.2} per group", con.trips(), con.trips() as f64 / memo.groups.len() as f64);
+        self.log_encoded();
+
+        self.entries = memo.entries.iter().map(|e| self.find(&mut con, *e)).collect::<Result<_, _>>()?;
+        self.ngroups = memo.groups.len();
+
+        if self.merged {
+            let merges: usize = redis::cmd("HLEN").arg("fwd").query(&mut con)?;
+            info!("redis: {} groups merged", merges);
+        }
+
+        Ok(hist)
+    }
+
+    fn add_stream(&mut self, groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        let mut con = self.pool.clone();
+
+        redis::cmd("FLUSHDB").exec(&mut con)?;
+        self.merged = false;
+        self.encoded = (0, 0);
+        con.reset();
+
+        for g in groups.by_ref() {
+            let start = Instant::now();
+
+            let eids: Vec<usize> = g.exprs.iter().map(|(j, _)| *j).collect();
+            self.insert_group(&mut con, g.id, &eids, g.exprs.iter().map(|(_, e)| e).collect(), &vec![])?;
 
             if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
                 warn!("histogram overflow")
             }
         }
 
-        self.entry = memo.entry;
-        self.ngroups = memo.groups.len();
+        self.entries = groups.entries().clone();
+        self.ngroups = groups.len().0;
+
+        info!("redis: {} round trips, {:.2} per group", con.trips(), con.trips() as f64 / self.ngroups as f64);
+        self.log_encoded();
 
         Ok(hist)
     }
@@ -82,7 +334,7 @@ impl Benchmark for BenchRedis {
         let mut hist =
             Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
 
-        let mut con = self.client.get_connection()?;
+        let mut con = self.pool.clone();
 
         let mut _tot = 0;
         for g in (0..memo.groups.len()).chain((0..1000).map(|_| { rng.gen_range(0..memo.groups.len()) })) {
@@ -90,15 +342,14 @@ impl Benchmark for BenchRedis {
             let start = Instant::now();
 
             let mut cmd = redis::cmd("HGETALL");
-            cmd.arg(g.to_string());
+            cmd.arg(self.find(&mut con, g)?);
 
-            let group_expressions: BTreeMap<String, String> = cmd.query(&mut con)?;
+            let group_expressions: BTreeMap<String, Vec<u8>> = cmd.query(&mut con)?;
 
             // do something with it
             let mut ids = vec![];
-            for (_, json) in group_expressions.iter() {
-                let value: Value = serde_json::from_str(json)?;
-                ids.push(value["moredata"].as_u64().unwrap() as usize);
+            for (_, bytes) in group_expressions.iter() {
+                ids.push(self.decode(bytes)?.moredata as usize);
             }
 
             if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
@@ -106,62 +357,544 @@ impl Benchmark for BenchRedis {
             }
 
             ids.sort();
-            assert_eq!(ids, memo.groups[g].exprs, "incorrect memo (do not use --shuffle merge?)")
+            ids.dedup();
+            assert_eq!(ids, memo.expected(g), "incorrect memo")
         }
 
         Ok(hist)
     }
 
     fn match_rules(&mut self) -> Result<Histogram<u64>, Box<dyn Error>> {
+        if self.match_mode == MatchMode::Server {
+            return self.match_server();
+        }
+
         let mut info = MatchInfo {
             visited_exprs: Default::default(),
             visited_groups: Default::default(),
+            root_groups: Default::default(),
+            shared: 0,
             hist: Histogram::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?,
             last: Instant::now(),
         };
 
-        self.explore_group(&mut info, self.entry)?;
+        for root in self.entries.clone() {
+            info.root_groups.clear();
+            self.explore_group(&mut info, root)?;
+        }
+
+        info!("match: {} roots, {} groups explored, {} shared with previous roots",
+            self.entries.len(), info.visited_groups.len(), info.shared);
+
+        if self.match_mode == MatchMode::Both {
+            let now = Instant::now();
+            let hist = self.match_server()?;
+            if hist.len() != info.hist.len() {
+                warn!("match: {} bindings on the server, {} on the client", hist.len(), info.hist.len());
+            }
+            log_summary(hist, "match (server)", now.elapsed(), false);
+        }
 
         Ok(info.hist)
     }
+
+    fn winners(&mut self, mut rng: ChaCha8Rng, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        let mut con = self.pool.clone();
+
+        let required: Vec<(usize, usize)> = memo.groups.iter().enumerate()
+            .flat_map(|(g, group)| group.winners.keys().map(move |p| (g, *p)))
+            .collect();
+        if required.is_empty() {
+            return Err("no physical expressions (use --physical)".into());
+        }
+
+        for (g, p) in required.clone().into_iter().chain((0..1000).map(|_| { required[rng.gen_range(0..required.len())] })) {
+
+            let start = Instant::now();
+
+            // best physical expression of group g for property p, as in optimize_inputs
+            let pid: String = redis::cmd("HGET").arg(format!("win:{}", g)).arg(p).query(&mut con)?;
+            let json: String = redis::cmd("HGET").arg(format!("phys:{}", g)).arg(&pid).query(&mut con)?;
+            let value: serde_json::Value = serde_json::from_str(&json)?;
+
+            if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
+                warn!("histogram overflow")
+            }
+
+            let winner = memo.groups[g].winners[&p];
+            assert_eq!(from_str::<usize>(&pid)?, winner, "incorrect winner");
+            assert_eq!(value["cost"].as_u64().unwrap(), memo.phys[winner].cost, "incorrect winner cost");
+        }
+
+        Ok(hist)
+    }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut con = self.pool.clone();
+
+        let gid = self.find(&mut con, g)?;
+        let group_expressions: BTreeMap<String, Vec<u8>> = redis::cmd("HGETALL").arg(gid).query(&mut con)?;
+
+        let mut ids = vec![];
+        for (_, bytes) in group_expressions.iter() {
+            ids.push(self.decode(bytes)?.moredata as usize);
+        }
+        Ok(ids)
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        let mut con = self.pool.clone();
+        Ok(self.find(&mut con, g)? == self.find(&mut con, h)?)
+    }
+}
+
+/// An expression as stored, both as the key to find duplicates and as the value in its group.
+/// Fields are in the order of JSON keys, so that all encodings are canonical.
+#[derive(Serialize, Deserialize)]
+struct StoredExpr {
+    children: Vec<usize>,
+    #[serde(default)]
+    extra: Vec<u64>,
+    moredata: u64,
+    #[serde(default)]
+    pred: Option<StoredScalar>, // scalars are stored inline
+    #[serde(rename = "type")]
+    op: usize,
 }
 
+#[derive(Serialize, Deserialize)]
+struct StoredScalar {
+    children: Vec<StoredScalar>,
+    op: usize,
+    value: u64,
+}
+
+impl StoredScalar {
+    fn new(scalars: &[RawScalar], id: usize) -> Self {
+        let s = &scalars[id];
+        StoredScalar {
+            children: s.children.iter().map(|c| StoredScalar::new(scalars, *c)).collect(),
+            op: s.op,
+            value: s.value,
+        }
+    }
+
+    fn put(&self, buf: &mut Vec<u8>) {
+        put_varint(buf, self.op as u64);
+        put_varint(buf, self.value);
+        put_varint(buf, self.children.len() as u64);
+        for c in self.children.iter() {
+            c.put(buf);
+        }
+    }
+
+    fn get(buf: &[u8], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let op = get_varint(buf, pos)? as usize;
+        let value = get_varint(buf, pos)?;
+        let n = get_varint(buf, pos)?;
+        let children = (0..n).map(|_| StoredScalar::get(buf, pos)).collect::<Result<_, _>>()?;
+        Ok(StoredScalar { children, op, value })
+    }
+}
+
+impl Encoding {
+    fn encode(&self, e: &StoredExpr) -> Vec<u8> {
+        match self {
+            Encoding::Json => {
+                // optional fields are left out, as scripts expect
+                let mut value = json!({
+                    "type": e.op,
+                    "children": e.children,
+                    "moredata": e.moredata,
+                });
+                if !e.extra.is_empty() {
+                    value["extra"] = json!(e.extra);
+                }
+                if let Some(pred) = &e.pred {
+                    value["pred"] = json!(pred);
+                }
+                value.to_string().into_bytes()
+            }
+            Encoding::Msgpack => rmp_serde::to_vec(e).unwrap(),
+            Encoding::Bincode => bincode::serialize(e).unwrap(),
+            Encoding::CompactBinary => {
+                // varints, with the lengths of lists first and a flag for the predicate
+                let mut buf = vec![];
+                put_varint(&mut buf, e.op as u64);
+                put_varint(&mut buf, e.moredata);
+                put_varint(&mut buf, e.children.len() as u64);
+                for c in e.children.iter() {
+                    put_varint(&mut buf, *c as u64);
+                }
+                put_varint(&mut buf, e.extra.len() as u64);
+                for x in e.extra.iter() {
+                    put_varint(&mut buf, *x);
+                }
+                match &e.pred {
+                    None => buf.push(0),
+                    Some(pred) => {
+                        buf.push(1);
+                        pred.put(&mut buf);
+                    }
+                }
+                buf
+            }
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<StoredExpr, Box<dyn Error>> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::Msgpack => rmp_serde::from_slice(bytes)?,
+            Encoding::Bincode => bincode::deserialize(bytes)?,
+            Encoding::CompactBinary => {
+                let pos = &mut 0;
+                let op = get_varint(bytes, pos)? as usize;
+                let moredata = get_varint(bytes, pos)?;
+                let n = get_varint(bytes, pos)?;
+                let children = (0..n).map(|_| get_varint(bytes, pos).map(|c| c as usize)).collect::<Result<_, _>>()?;
+                let n = get_varint(bytes, pos)?;
+                let extra = (0..n).map(|_| get_varint(bytes, pos)).collect::<Result<_, _>>()?;
+                let pred = match bytes.get(*pos) {
+                    Some(0) => None,
+                    Some(_) => {
+                        *pos += 1;
+                        Some(StoredScalar::get(bytes, pos)?)
+                    }
+                    None => return Err("truncated expression".into()),
+                };
+                StoredExpr { children, extra, moredata, pred, op }
+            }
+        })
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Result<u64, Box<dyn Error>> {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let b = *buf.get(*pos).ok_or("truncated expression")?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 {
+            return Ok(v);
+        }
+        shift += 7;
+    }
+}
 
 struct MatchInfo {
     visited_exprs: HashSet<usize>,
     visited_groups: HashSet<usize>,
+    root_groups: HashSet<usize>, // reached from the current root
+    shared: usize, // groups already explored from previous roots
     hist: Histogram<u64>,
     last: Instant,
 }
 
 impl BenchRedis {
-    fn optimize_expression(&mut self, info: &mut MatchInfo, expr_id: usize, json: &String) -> Result<(),Box<dyn Error>>{
+    fn decode(&self, bytes: &[u8]) -> Result<StoredExpr, Box<dyn Error>> {
+        self.encoding.decode(bytes)
+    }
+
+    /// Key of an expression, with children as currently merged, so that duplicates have the same key.
+    fn expr_key(&mut self, con: &mut dyn ConnectionLike, e: &RawExpr, scalars: &[RawScalar]) -> Result<(Vec<u8>, Vec<usize>), Box<dyn Error>> {
+        let mut children = e.children.iter().map(|c| self.find(con, *c)).collect::<Result<Vec<_>, _>>()?;
+        if self.canonicalize && e.op == OP_JOIN {
+            children.sort();
+        }
+        let key = self.encoding.encode(&StoredExpr {
+            children: children.clone(),
+            extra: e.extra.clone(),
+            moredata: e.data as u64,
+            pred: e.pred.map(|p| StoredScalar::new(scalars, p)),
+            op: e.op,
+        });
+        self.encoded.0 += 1;
+        self.encoded.1 += key.len();
+        Ok((key, children))
+    }
+
+    fn log_encoded(&self) {
+        if let Some(bytes) = self.encoded.1.checked_div(self.encoded.0) {
+            info!("redis: {} bytes per expression, as key and as value ({} expressions)", bytes, self.encoded.0);
+        }
+    }
+
+    /// Store the expressions of group i, or find the group of a duplicate, merging groups when
+    /// expressions are found in more than one.
+    fn insert_group(&mut self, con: &mut dyn ConnectionLike, i: usize, eids: &[usize], exprs: Vec<&RawExpr>, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        if self.insert_mode != InsertMode::PerCommand {
+            return self.insert_batch(con, i, eids, exprs, scalars);
+        }
+
+        let mut gid = i;
+        let mut owned = false; // some key already names gid
+        let mut fresh = vec![];
+        for (j, e) in exprs.iter().enumerate() {
+            let (key, children) = self.expr_key(con, e, scalars)?;
+
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(&key)
+                .arg(gid)
+                .arg("NX")
+                .arg("GET");
+            let result: redis::Value = cmd.query(con)?;
+            match redis::from_redis_value::<usize>(&result) {
+                Ok(id) => {
+                    let id = self.find(con, id)?;
+                    if !owned {
+                        // the group already exists, forward its id too
+                        if id != gid {
+                            redis::cmd("HSET").arg("fwd").arg(gid).arg(id).exec(con)?;
+                            self.merged = true;
+                        }
+                        gid = id;
+                        owned = true;
+                    } else if id != gid {
+                        // store first, so that merges also rewrite these
+                        self.store(con, gid, eids, &fresh)?;
+                        fresh.clear();
+                        gid = self.merge(con, gid, id)?;
+                    }
+                }
+                Err(_) => {
+                    owned = true;
+                    fresh.push((j, key, children));
+                }
+            }
+        }
+
+        // duplicates are already stored
+        self.store(con, gid, eids, &fresh)
+    }
+
+    /// Insert a group as insert_group, but looking up all its expressions at once, in a pipeline,
+    /// a transaction or a script, and merging groups only after storing the new expressions.
+    fn insert_batch(&mut self, con: &mut dyn ConnectionLike, i: usize, eids: &[usize], exprs: Vec<&RawExpr>, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        let keys = exprs.iter().map(|e| self.expr_key(con, e, scalars)).collect::<Result<Vec<_>, _>>()?;
+
+        let (mut gid, others) = if self.insert_mode == InsertMode::Script {
+            let script = redis::Script::new(INSERT_SCRIPT);
+            let mut invocation = script.prepare_invoke();
+            for ((key, _), j) in keys.iter().zip(eids.iter()) {
+                invocation.key(key).arg(*j);
+            }
+            let (gid, forwarded, others): (usize, usize, Vec<usize>) = invocation.arg(i).invoke(con)?;
+            if forwarded > 0 || !others.is_empty() {
+                self.merged = true;
+            }
+            (gid, others)
+        } else {
+            // all new expressions name group i, which is forwarded if it turns out to exist
+            let mut pipe = redis::pipe();
+            if self.insert_mode == InsertMode::Transaction {
+                pipe.atomic();
+            }
+            for (key, _) in keys.iter() {
+                pipe.cmd("SET").arg(key).arg(i).arg("NX").arg("GET");
+            }
+            let results: Vec<redis::Value> = pipe.query(con)?;
+
+            let mut gid = i;
+            let mut owned = false;
+            let mut fresh = vec![];
+            let mut others = vec![];
+            for (j, result) in results.iter().enumerate() {
+                match redis::from_redis_value::<usize>(result) {
+                    Ok(id) => {
+                        let id = self.find(con, id)?;
+                        if !owned {
+                            if id != gid {
+                                redis::cmd("HSET").arg("fwd").arg(gid).arg(id).exec(con)?;
+                                self.merged = true;
+                            }
+                            gid = id;
+                            owned = true;
+                        } else if id != gid {
+                            others.push(id);
+                        }
+                    }
+                    Err(_) => {
+                        owned = true;
+                        fresh.push((j, keys[j].0.clone(), keys[j].1.clone()));
+                    }
+                }
+            }
+            self.store(con, gid, eids, &fresh)?;
+            (gid, others)
+        };
+
+        for other in others {
+            gid = self.merge(con, gid, other)?;
+        }
+        Ok(())
+    }
+
+    fn store(&self, con: &mut dyn ConnectionLike, gid: usize, eids: &[usize], fresh: &[(usize, Vec<u8>, Vec<usize>)]) -> Result<(), Box<dyn Error>> {
+        if fresh.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        if self.insert_mode == InsertMode::Transaction {
+            pipe.atomic();
+        }
+        let cmd = pipe.cmd("HSET").arg(gid);
+        for (j, key, _) in fresh.iter() {
+            cmd.arg(eids[*j]).arg(key);
+        }
+        // parent expressions are rewritten if their children are merged
+        for (_, key, children) in fresh.iter() {
+            for c in children.iter() {
+                pipe.cmd("SADD").arg(format!("parents:{}", c)).arg(key);
+            }
+        }
+        pipe.exec(con)?;
+        Ok(())
+    }
+
+    /// Current group of a group id, following the forwarding of merged groups.
+    fn find(&self, con: &mut dyn ConnectionLike, mut gid: usize) -> Result<usize, Box<dyn Error>> {
+        if self.merged {
+            while let Some(next) = redis::cmd("HGET").arg("fwd").arg(gid).query::<Option<usize>>(con)? {
+                gid = next;
+            }
+        }
+        Ok(gid)
+    }
+
+    /// Merge two groups into the one with the lowest id and rewrite the keys of expressions
+    /// with the other as a child, which may find more duplicates and thus more groups to merge.
+    fn merge(&mut self, con: &mut dyn ConnectionLike, a: usize, b: usize) -> Result<usize, Box<dyn Error>> {
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.find(con, a)?, self.find(con, b)?);
+            if a == b {
+                continue;
+            }
+            let (winner, loser) = (min(a, b), max(a, b));
+            self.merged = true;
+
+            let exprs: BTreeMap<String, Vec<u8>> = redis::cmd("HGETALL").arg(loser).query(con)?;
+            let mut pipe = redis::pipe();
+            if !exprs.is_empty() {
+                let cmd = pipe.cmd("HSET").arg(winner);
+                for (eid, key) in exprs.iter() {
+                    cmd.arg(eid).arg(key);
+                }
+            }
+            pipe.cmd("DEL").arg(loser)
+                .cmd("HSET").arg("fwd").arg(loser).arg(winner);
+            pipe.exec(con)?;
+
+            let parents: Vec<Vec<u8>> = redis::cmd("SMEMBERS").arg(format!("parents:{}", loser)).query(con)?;
+            redis::cmd("DEL").arg(format!("parents:{}", loser)).exec(con)?;
+            for key in parents {
+                let owner: Option<usize> = redis::cmd("GET").arg(&key).query(con)?;
+                let Some(owner) = owner else {
+                    continue; // already rewritten
+                };
+                let owner = self.find(con, owner)?;
+
+                let mut expr = self.decode(&key)?;
+                for c in expr.children.iter_mut().filter(|c| **c == loser) {
+                    *c = winner;
+                }
+                if self.canonicalize && expr.op == OP_JOIN {
+                    expr.children.sort();
+                }
+                let children = expr.children.clone();
+                let rekeyed = self.encoding.encode(&expr);
+
+                // the copy stored in the group is found by its contents
+                let stored: BTreeMap<String, Vec<u8>> = redis::cmd("HGETALL").arg(owner).query(con)?;
+                let mut pipe = redis::pipe();
+                for (eid, _) in stored.iter().filter(|(_, stored)| **stored == key) {
+                    pipe.cmd("HSET").arg(owner).arg(eid).arg(&rekeyed);
+                }
+                pipe.cmd("DEL").arg(&key);
+                for c in children.iter() {
+                    pipe.cmd("SREM").arg(format!("parents:{}", c)).arg(&key)
+                        .cmd("SADD").arg(format!("parents:{}", c)).arg(&rekeyed);
+                }
+                pipe.exec(con)?;
+
+                let result: redis::Value = redis::cmd("SET").arg(&rekeyed).arg(owner).arg("NX").arg("GET").query(con)?;
+                if let Ok(other) = redis::from_redis_value::<usize>(&result) {
+                    // the parent is now a duplicate of an expression in another group
+                    pending.push((owner, other));
+                }
+            }
+        }
+
+        self.find(con, a)
+    }
+
+    /// Match the rule inside the server, with a script that returns only the bindings.
+    fn match_server(&mut self) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        let mut con = self.pool.clone();
+        let script = redis::Script::new(MATCH_SCRIPT);
+
+        let start = Instant::now();
+
+        let (explored, shared, bindings): (usize, usize, Vec<String>) =
+            script.arg(self.entries.clone()).invoke(&mut con)?;
+
+        // small cheat, as with calcite: use the average time and the number of matches
+        let matches = bindings.len() as u64 / 2;
+        if let Some(average) = (start.elapsed().as_nanos() as u64).checked_div(matches) {
+            if hist.record_n(average, matches).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+
+        info!("match (server): {} roots, {} groups explored, {} shared with previous roots",
+            self.entries.len(), explored, shared);
+
+        Ok(hist)
+    }
+
+    fn optimize_expression(&mut self, info: &mut MatchInfo, expr_id: usize, bytes: &[u8]) -> Result<(),Box<dyn Error>>{
         if info.visited_exprs.insert(expr_id) {
-            let top_expr: Value = serde_json::from_str(json)?;
+            let top_expr = self.decode(bytes)?;
 
-            let children = top_expr["children"].as_array().unwrap();
+            let children = top_expr.children;
 
             // explore children first
             for c in children.iter() {
-                self.explore_group(info, c.as_i64().unwrap() as usize)?;
+                self.explore_group(info, *c)?;
             }
 
             // top_matches in optimize_expression task
             let mut _picks = vec![];
-            if top_expr["type"].as_i64().unwrap() == 1 {
+            if top_expr.op == 1 {
 
                 // match_and_pick_expr in apply_rule task
-                let mut con = self.client.get_connection()?;
+                let mut con = self.pool.clone();
 
                 let mut cmd = redis::cmd("HGETALL");
-                cmd.arg(children[0].as_i64().unwrap());
+                cmd.arg(children[0]);
 
-                let bot_expressions: BTreeMap<String, String> = cmd.query(&mut con)?;
+                let bot_expressions: BTreeMap<String, Vec<u8>> = cmd.query(&mut con)?;
 
-                for (_,json) in bot_expressions.iter() {
-                    let bot_expr: Value = serde_json::from_str(json).unwrap();
-                    if bot_expr["type"].as_i64().unwrap() == 2 {
-                        _picks.push(bot_expr["children"].clone());
+                for (_,bytes) in bot_expressions.iter() {
+                    let bot_expr = self.decode(bytes)?;
+                    if bot_expr.op == 2 {
+                        _picks.push(bot_expr.children);
 
                         let now = Instant::now();
                         if let Err(_) = info
@@ -181,16 +914,19 @@ impl BenchRedis {
 
     fn explore_group(&mut self, info: &mut MatchInfo, group_id: usize) -> Result<(),Box<dyn Error>> {
         if info.visited_groups.insert(group_id) {
-            let mut con = self.client.get_connection()?;
+            info.root_groups.insert(group_id);
+            let mut con = self.pool.clone();
 
             let mut cmd = redis::cmd("HGETALL");
             cmd.arg(group_id.to_string());
 
-            let group_expressions: BTreeMap<String, String> = cmd.query(&mut con)?;
+            let group_expressions: BTreeMap<String, Vec<u8>> = cmd.query(&mut con)?;
 
-            for (id,json) in group_expressions.iter() {
-                self.optimize_expression(info, from_str::<usize>(id)?, json)?;
+            for (id,bytes) in group_expressions.iter() {
+                self.optimize_expression(info, from_str::<usize>(id)?, bytes)?;
             }
+        } else if info.root_groups.insert(group_id) {
+            info.shared += 1;
         }
 
         Ok(())
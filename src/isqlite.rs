@@ -0,0 +1,623 @@
+use crate::generator::{Generator, RawExpr, RawMemo, RawScalar, OP_FILTER, OP_JOIN};
+use crate::Benchmark;
+use clap::ValueEnum;
+use hdrhistogram::Histogram;
+use log::{info, warn};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use rusqlite::{params, Connection};
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum Schema { Normalized, Blob }
+
+#[derive(Clone, ValueEnum, PartialEq)]
+pub enum Journal { Wal, Rollback }
+
+const NORMALIZED: &str = r#"
+CREATE TABLE IF NOT EXISTS grp (id INTEGER PRIMARY KEY, fwd INTEGER);
+CREATE TABLE IF NOT EXISTS expr (id INTEGER PRIMARY KEY, group_id INTEGER NOT NULL, op INTEGER NOT NULL,
+    data INTEGER NOT NULL, hash INTEGER NOT NULL, pred INTEGER);
+CREATE TABLE IF NOT EXISTS child (expr_id INTEGER NOT NULL, pos INTEGER NOT NULL, group_id INTEGER NOT NULL,
+    PRIMARY KEY (expr_id, pos)) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS extra (expr_id INTEGER NOT NULL, pos INTEGER NOT NULL, value INTEGER NOT NULL,
+    PRIMARY KEY (expr_id, pos)) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS scalar (id INTEGER PRIMARY KEY, op INTEGER NOT NULL, value INTEGER NOT NULL,
+    left INTEGER, right INTEGER);
+CREATE INDEX IF NOT EXISTS expr_group ON expr (group_id);
+CREATE INDEX IF NOT EXISTS child_group ON child (group_id);
+"#;
+
+const BLOB: &str = r#"
+CREATE TABLE IF NOT EXISTS grp (id INTEGER PRIMARY KEY, fwd INTEGER);
+CREATE TABLE IF NOT EXISTS expr (id INTEGER PRIMARY KEY, group_id INTEGER NOT NULL, hash INTEGER NOT NULL,
+    body BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS parent (group_id INTEGER NOT NULL, expr_id INTEGER NOT NULL);
+CREATE INDEX IF NOT EXISTS expr_group ON expr (group_id);
+CREATE INDEX IF NOT EXISTS parent_group ON parent (group_id);
+"#;
+
+const DROP: &str = r#"
+DROP TABLE IF EXISTS grp;
+DROP TABLE IF EXISTS expr;
+DROP TABLE IF EXISTS child;
+DROP TABLE IF EXISTS extra;
+DROP TABLE IF EXISTS scalar;
+DROP TABLE IF EXISTS parent;
+"#;
+
+/// A memo in SQLite with its own schema, either normalized into tables of groups, expressions
+/// and children, or with each expression as a blob.
+pub struct BenchSqlite {
+    conn: Connection,
+    entries: Vec<usize>,
+    canonicalize: bool,
+    merged: bool, // groups have been merged, so ids need to be forwarded
+    schema: Schema,
+    dedup_index: bool,
+    cached: bool, // statements are prepared once and cached
+    transactions: bool, // one transaction per group
+}
+
+/// Identity of an expression, with children as currently merged, used to find duplicates.
+struct Key {
+    op: usize,
+    data: u64,
+    children: Vec<usize>,
+    body: Vec<u8>, // the whole expression, with the blob schema
+    hash: i64,
+}
+
+/// An expression as read from a group.
+struct Row {
+    id: usize,
+    op: usize,
+    data: u64,
+    children: Vec<usize>,
+}
+
+impl BenchSqlite {
+    pub fn new(database: &str, canonicalize: bool, schema: Schema, dedup_index: bool, journal: Journal, statement_cache: usize, transactions: bool) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(database)?;
+
+        let requested = match journal {
+            Journal::Wal => "wal",
+            Journal::Rollback => "delete",
+        };
+        let mode: String = conn.query_row(&format!("PRAGMA journal_mode = {}", requested), [], |r| r.get(0))?;
+        // an in-memory database always keeps its journal in memory
+        if mode != requested && database != ":memory:" {
+            warn!("sqlite: journal mode is {} instead of {}", mode, requested);
+        }
+        conn.set_prepared_statement_cache_capacity(statement_cache);
+
+        let mut bench = BenchSqlite {
+            conn,
+            entries: vec![],
+            canonicalize,
+            merged: false,
+            schema,
+            dedup_index,
+            cached: statement_cache > 0,
+            transactions,
+        };
+
+        // a memo left by a previous run may have merged groups
+        bench.create()?;
+        bench.merged = bench.conn.query_row("SELECT EXISTS (SELECT 1 FROM grp WHERE fwd IS NOT NULL)", [], |r| r.get(0))?;
+
+        Ok(bench)
+    }
+
+    fn create(&self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch(match self.schema {
+            Schema::Normalized => NORMALIZED,
+            Schema::Blob => BLOB,
+        })?;
+        match self.dedup_index {
+            true => self.conn.execute_batch("CREATE INDEX IF NOT EXISTS expr_hash ON expr (hash)")?,
+            false => self.conn.execute_batch("DROP INDEX IF EXISTS expr_hash")?,
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch(DROP)?;
+        self.create()?;
+        self.merged = false;
+        Ok(())
+    }
+
+    /// Run a statement, prepared once and cached unless caching is disabled.
+    fn execute(&self, sql: &str, params: impl rusqlite::Params) -> Result<usize, Box<dyn Error>> {
+        Ok(match self.cached {
+            true => self.conn.prepare_cached(sql)?.execute(params)?,
+            false => self.conn.prepare(sql)?.execute(params)?,
+        })
+    }
+
+    /// Run a query, prepared once and cached unless caching is disabled, collecting its rows.
+    fn query<T>(&self, sql: &str, params: impl rusqlite::Params, f: impl FnMut(&rusqlite::Row) -> rusqlite::Result<T>) -> Result<Vec<T>, Box<dyn Error>> {
+        Ok(match self.cached {
+            true => self.conn.prepare_cached(sql)?.query_map(params, f)?.collect::<Result<_, _>>()?,
+            false => self.conn.prepare(sql)?.query_map(params, f)?.collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn log_size(&self) -> Result<(), Box<dyn Error>> {
+        let pages: u64 = self.conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+        let page_size: u64 = self.conn.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+        let merges: usize = self.conn.query_row("SELECT COUNT(*) FROM grp WHERE fwd IS NOT NULL", [], |r| r.get(0))?;
+        info!("sqlite: {} bytes in {} pages, {} groups merged", pages * page_size, pages, merges);
+        Ok(())
+    }
+
+    /// Add a group in its own transaction, if enabled.
+    fn add_group(&mut self, i: usize, eids: &[usize], exprs: Vec<&RawExpr>, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        if self.transactions {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        self.insert_group(i, eids, exprs, scalars)?;
+        if self.transactions {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
+
+impl Benchmark for BenchSqlite {
+    fn add(&mut self, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        self.clear()?;
+
+        for (i, g) in memo.groups.iter().enumerate() {
+            let start = Instant::now();
+
+            let exprs: Vec<&RawExpr> = g.exprs.iter().map(|j| &memo.exprs[*j]).collect();
+            self.add_group(i, &g.exprs, exprs, &memo.scalars)?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+
+        self.entries = memo.entries.iter().map(|e| self.find(*e)).collect::<Result<_, _>>()?;
+        self.log_size()?;
+
+        Ok(hist)
+    }
+
+    fn add_stream(&mut self, groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        self.clear()?;
+
+        for g in groups.by_ref() {
+            let start = Instant::now();
+
+            let eids: Vec<usize> = g.exprs.iter().map(|(j, _)| *j).collect();
+            self.add_group(g.id, &eids, g.exprs.iter().map(|(_, e)| e).collect(), &[])?;
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+        }
+
+        self.entries = groups.entries().clone();
+        self.log_size()?;
+
+        Ok(hist)
+    }
+
+    fn retrieve(&mut self, mut rng: ChaCha8Rng, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        for g in (0..memo.groups.len()).chain((0..1000).map(|_| { rng.gen_range(0..memo.groups.len()) })) {
+
+            let start = Instant::now();
+
+            let group_expressions = self.group(self.find(g)?)?;
+
+            // do something with it
+            let mut ids: Vec<usize> = group_expressions.iter().map(|e| e.data as usize).collect();
+
+            if hist.record(start.elapsed().as_nanos() as u64).is_err() {
+                warn!("histogram overflow")
+            }
+
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids, memo.expected(g), "incorrect memo")
+        }
+
+        Ok(hist)
+    }
+
+    fn match_rules(&mut self) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let mut info = MatchInfo {
+            visited_exprs: Default::default(),
+            visited_groups: Default::default(),
+            root_groups: Default::default(),
+            shared: 0,
+            hist: Histogram::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?,
+            last: Instant::now(),
+        };
+
+        for root in self.entries.clone() {
+            info.root_groups.clear();
+            self.explore_group(&mut info, root)?;
+        }
+
+        info!("match: {} roots, {} groups explored, {} shared with previous roots",
+            self.entries.len(), info.visited_groups.len(), info.shared);
+
+        Ok(info.hist)
+    }
+
+    fn payloads(&mut self, g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        Ok(self.group(self.find(g)?)?.iter().map(|e| e.data as usize).collect())
+    }
+
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.find(g)? == self.find(h)?)
+    }
+}
+
+struct MatchInfo {
+    visited_exprs: HashSet<usize>,
+    visited_groups: HashSet<usize>,
+    root_groups: HashSet<usize>, // reached from the current root
+    shared: usize, // groups already explored from previous roots
+    hist: Histogram<u64>,
+    last: Instant,
+}
+
+impl BenchSqlite {
+    /// Key of an expression, with children as currently merged, so that duplicates have the same key.
+    fn key(&self, e: &RawExpr, scalars: &[RawScalar]) -> Result<Key, Box<dyn Error>> {
+        let mut children = e.children.iter().map(|c| self.find(*c)).collect::<Result<Vec<_>, _>>()?;
+        if self.canonicalize && e.op == OP_JOIN {
+            children.sort();
+        }
+        let body = match self.schema {
+            Schema::Normalized => vec![],
+            Schema::Blob => encode(e, &children, scalars),
+        };
+        let mut key = Key { op: e.op, data: e.data as u64, children, body, hash: 0 };
+        key.hash = self.hash(&key);
+        Ok(key)
+    }
+
+    /// 64-bit FNV-1a, which unlike the standard hasher is stable, as hashes are kept in the database.
+    fn hash(&self, key: &Key) -> i64 {
+        let fnv = |bytes: &[u8]| bytes.iter().fold(0xcbf29ce484222325u64, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3));
+        let hash = match self.schema {
+            Schema::Normalized => {
+                let fields: Vec<u8> = [key.op as u64, key.data].into_iter()
+                    .chain(key.children.iter().map(|c| *c as u64))
+                    .flat_map(u64::to_le_bytes)
+                    .collect();
+                fnv(&fields)
+            }
+            Schema::Blob => fnv(&key.body),
+        };
+        hash as i64
+    }
+
+    /// Group of an expression equal to the key, other than the given one, by its hash and then
+    /// by its contents.
+    fn lookup(&self, key: &Key, except: Option<usize>) -> Result<Option<usize>, Box<dyn Error>> {
+        let except = except.map_or(-1, |id| id as i64);
+        match self.schema {
+            Schema::Normalized => {
+                let candidates = self.query("SELECT id, group_id FROM expr WHERE hash = ?1 AND op = ?2 AND data = ?3 AND id != ?4",
+                    params![key.hash, key.op, key.data, except], |r| Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?)))?;
+                for (id, gid) in candidates {
+                    let children = self.query("SELECT group_id FROM child WHERE expr_id = ?1 ORDER BY pos",
+                        [id], |r| r.get::<_, usize>(0))?;
+                    if children == key.children {
+                        return Ok(Some(gid));
+                    }
+                }
+                Ok(None)
+            }
+            Schema::Blob => {
+                let found = self.query("SELECT group_id FROM expr WHERE hash = ?1 AND body = ?2 AND id != ?3 LIMIT 1",
+                    params![key.hash, key.body, except], |r| r.get::<_, usize>(0))?;
+                Ok(found.first().copied())
+            }
+        }
+    }
+
+    /// Store the expressions of group i, or find the group of a duplicate, merging groups when
+    /// expressions are found in more than one.
+    fn insert_group(&mut self, i: usize, eids: &[usize], exprs: Vec<&RawExpr>, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        let mut gid = i;
+        let mut owned = false; // some expression is already in gid
+        for (j, e) in exprs.iter().enumerate() {
+            let key = self.key(e, scalars)?;
+
+            match self.lookup(&key, None)? {
+                Some(id) if !owned => {
+                    // the group already exists, forward its id too
+                    if id != gid {
+                        self.execute("INSERT OR REPLACE INTO grp (id, fwd) VALUES (?1, ?2)", [gid, id])?;
+                        self.merged = true;
+                    }
+                    gid = id;
+                    owned = true;
+                }
+                Some(id) => {
+                    if id != gid {
+                        gid = self.merge(gid, id)?;
+                    }
+                }
+                None => {
+                    if !owned {
+                        self.execute("INSERT OR IGNORE INTO grp (id) VALUES (?1)", [gid])?;
+                        owned = true;
+                    }
+                    self.store(gid, eids[j], e, &key, scalars)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&self, gid: usize, eid: usize, e: &RawExpr, key: &Key, scalars: &[RawScalar]) -> Result<(), Box<dyn Error>> {
+        match self.schema {
+            Schema::Normalized => {
+                if let Some(pred) = e.pred {
+                    self.store_scalar(scalars, pred)?;
+                }
+                self.execute("INSERT INTO expr (id, group_id, op, data, hash, pred) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![eid, gid, key.op, key.data, key.hash, e.pred])?;
+                for (pos, c) in key.children.iter().enumerate() {
+                    self.execute("INSERT INTO child (expr_id, pos, group_id) VALUES (?1, ?2, ?3)", [eid, pos, *c])?;
+                }
+                for (pos, v) in e.extra.iter().enumerate() {
+                    self.execute("INSERT INTO extra (expr_id, pos, value) VALUES (?1, ?2, ?3)", params![eid, pos, *v as i64])?;
+                }
+            }
+            Schema::Blob => {
+                self.execute("INSERT INTO expr (id, group_id, hash, body) VALUES (?1, ?2, ?3, ?4)",
+                    params![eid, gid, key.hash, key.body])?;
+                // parent expressions are rewritten if their children are merged
+                for c in key.children.iter() {
+                    self.execute("INSERT INTO parent (group_id, expr_id) VALUES (?1, ?2)", [*c, eid])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a scalar tree by the ids of the raw memo, stopping at subtrees already stored.
+    fn store_scalar(&self, scalars: &[RawScalar], id: usize) -> Result<(), Box<dyn Error>> {
+        let s = &scalars[id];
+        let stored = self.execute("INSERT OR IGNORE INTO scalar (id, op, value, left, right) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, s.op, s.value as i64, s.children.first(), s.children.get(1)])?;
+        if stored > 0 {
+            for c in s.children.iter() {
+                self.store_scalar(scalars, *c)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Current group of a group id, following the forwarding of merged groups.
+    fn find(&self, mut gid: usize) -> Result<usize, Box<dyn Error>> {
+        if self.merged {
+            while let Some(Some(next)) = self.query("SELECT fwd FROM grp WHERE id = ?1", [gid], |r| r.get::<_, Option<usize>>(0))?.first() {
+                gid = *next;
+            }
+        }
+        Ok(gid)
+    }
+
+    /// Merge two groups into the one with the lowest id and rewrite the children of expressions
+    /// with the other as a child, which may find more duplicates and thus more groups to merge.
+    fn merge(&mut self, a: usize, b: usize) -> Result<usize, Box<dyn Error>> {
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.find(a)?, self.find(b)?);
+            if a == b {
+                continue;
+            }
+            let (winner, loser) = (min(a, b), max(a, b));
+            self.merged = true;
+
+            self.execute("UPDATE expr SET group_id = ?1 WHERE group_id = ?2", [winner, loser])?;
+            self.execute("INSERT OR REPLACE INTO grp (id, fwd) VALUES (?1, ?2)", [loser, winner])?;
+
+            let parents = match self.schema {
+                Schema::Normalized => {
+                    let parents = self.query("SELECT DISTINCT expr_id FROM child WHERE group_id = ?1", [loser], |r| r.get::<_, usize>(0))?;
+                    self.execute("UPDATE child SET group_id = ?1 WHERE group_id = ?2", [winner, loser])?;
+                    parents
+                }
+                Schema::Blob => {
+                    let parents = self.query("SELECT DISTINCT expr_id FROM parent WHERE group_id = ?1", [loser], |r| r.get::<_, usize>(0))?;
+                    self.execute("UPDATE parent SET group_id = ?1 WHERE group_id = ?2", [winner, loser])?;
+                    parents
+                }
+            };
+
+            for id in parents {
+                let (owner, key) = self.rekey(id, loser, winner)?;
+                if let Some(other) = self.lookup(&key, Some(id))? {
+                    if other != owner {
+                        // the parent is now a duplicate of an expression in another group
+                        pending.push((owner, other));
+                    }
+                }
+            }
+        }
+
+        self.find(a)
+    }
+
+    /// Replace a merged child of an expression and update its hash, returning its group and key.
+    fn rekey(&self, id: usize, loser: usize, winner: usize) -> Result<(usize, Key), Box<dyn Error>> {
+        match self.schema {
+            Schema::Normalized => {
+                let (owner, op, data) = self.conn.query_row("SELECT group_id, op, data FROM expr WHERE id = ?1", [id],
+                    |r| Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?, r.get::<_, u64>(2)?)))?;
+                let mut children = self.query("SELECT group_id FROM child WHERE expr_id = ?1 ORDER BY pos", [id], |r| r.get::<_, usize>(0))?;
+                if self.canonicalize && op == OP_JOIN && !children.is_sorted() {
+                    children.sort();
+                    for (pos, c) in children.iter().enumerate() {
+                        self.execute("UPDATE child SET group_id = ?1 WHERE expr_id = ?2 AND pos = ?3", [*c, id, pos])?;
+                    }
+                }
+                let mut key = Key { op, data, children, body: vec![], hash: 0 };
+                key.hash = self.hash(&key);
+                self.execute("UPDATE expr SET hash = ?1 WHERE id = ?2", params![key.hash, id])?;
+                Ok((owner, key))
+            }
+            Schema::Blob => {
+                let (owner, mut body) = self.conn.query_row("SELECT group_id, body FROM expr WHERE id = ?1", [id],
+                    |r| Ok((r.get::<_, usize>(0)?, r.get::<_, Vec<u8>>(1)?)))?;
+                let (op, data, mut children) = header(&body);
+                for c in children.iter_mut().filter(|c| **c == loser) {
+                    *c = winner;
+                }
+                if self.canonicalize && op == OP_JOIN {
+                    children.sort();
+                }
+                set_children(&mut body, &children);
+                let mut key = Key { op, data, children, body, hash: 0 };
+                key.hash = self.hash(&key);
+                self.execute("UPDATE expr SET hash = ?1, body = ?2 WHERE id = ?3", params![key.hash, key.body, id])?;
+                Ok((owner, key))
+            }
+        }
+    }
+
+    /// Expressions of a group, with their children.
+    fn group(&self, gid: usize) -> Result<Vec<Row>, Box<dyn Error>> {
+        match self.schema {
+            Schema::Normalized => {
+                let cells = self.query("SELECT e.id, e.op, e.data, c.group_id FROM expr e LEFT JOIN child c ON c.expr_id = e.id \
+                    WHERE e.group_id = ?1 ORDER BY e.id, c.pos", [gid],
+                    |r| Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?, r.get::<_, u64>(2)?, r.get::<_, Option<usize>>(3)?)))?;
+                let mut rows: Vec<Row> = vec![];
+                for (id, op, data, child) in cells {
+                    if rows.last().is_none_or(|row| row.id != id) {
+                        rows.push(Row { id, op, data, children: vec![] });
+                    }
+                    if let Some(c) = child {
+                        rows.last_mut().unwrap().children.push(c);
+                    }
+                }
+                Ok(rows)
+            }
+            Schema::Blob => {
+                self.query("SELECT id, body FROM expr WHERE group_id = ?1", [gid], |r| {
+                    let (op, data, children) = header(&r.get::<_, Vec<u8>>(1)?);
+                    Ok(Row { id: r.get(0)?, op, data, children })
+                })
+            }
+        }
+    }
+
+    fn optimize_expression(&mut self, info: &mut MatchInfo, top_expr: &Row) -> Result<(), Box<dyn Error>> {
+        if info.visited_exprs.insert(top_expr.id) {
+
+            // explore children first
+            for c in top_expr.children.iter() {
+                self.explore_group(info, *c)?;
+            }
+
+            // top_matches in optimize_expression task
+            let mut _picks = vec![];
+            if top_expr.op == OP_FILTER {
+
+                // match_and_pick_expr in apply_rule task
+                for bot_expr in self.group(top_expr.children[0])? {
+                    if bot_expr.op == OP_JOIN {
+                        _picks.push(bot_expr.children);
+
+                        let now = Instant::now();
+                        if info
+                            .hist
+                            .record(now.duration_since(info.last).as_nanos() as u64)
+                            .is_err()
+                        {
+                            warn!("histogram overflow")
+                        }
+                        info.last = now;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn explore_group(&mut self, info: &mut MatchInfo, group_id: usize) -> Result<(), Box<dyn Error>> {
+        if info.visited_groups.insert(group_id) {
+            info.root_groups.insert(group_id);
+            for expr in self.group(group_id)? {
+                self.optimize_expression(info, &expr)?;
+            }
+        } else if info.root_groups.insert(group_id) {
+            info.shared += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Body of an expression in the blob schema: operator, payload and children first, so that
+/// children can be rewritten in place, then extra payload and the predicate tree, all in
+/// fixed-width little-endian fields.
+fn encode(e: &RawExpr, children: &[usize], scalars: &[RawScalar]) -> Vec<u8> {
+    let mut body = vec![e.op as u8];
+    body.extend_from_slice(&(e.data as u64).to_le_bytes());
+    body.push(children.len() as u8);
+    for c in children.iter() {
+        body.extend_from_slice(&(*c as u64).to_le_bytes());
+    }
+    body.extend_from_slice(&(e.extra.len() as u32).to_le_bytes());
+    for v in e.extra.iter() {
+        body.extend_from_slice(&v.to_le_bytes());
+    }
+    match e.pred {
+        Some(pred) => {
+            body.push(1);
+            put_scalar(&mut body, scalars, pred);
+        }
+        None => body.push(0),
+    }
+    body
+}
+
+fn put_scalar(body: &mut Vec<u8>, scalars: &[RawScalar], id: usize) {
+    let s = &scalars[id];
+    body.push(s.op as u8);
+    body.extend_from_slice(&s.value.to_le_bytes());
+    body.push(s.children.len() as u8);
+    for c in s.children.iter() {
+        put_scalar(body, scalars, *c);
+    }
+}
+
+/// Operator, payload and children of an expression in the blob schema.
+fn header(body: &[u8]) -> (usize, u64, Vec<usize>) {
+    let word = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+    let children = (0..body[9] as usize).map(|k| word(10 + 8 * k) as usize).collect();
+    (body[0] as usize, word(1), children)
+}
+
+fn set_children(body: &mut [u8], children: &[usize]) {
+    for (k, c) in children.iter().enumerate() {
+        body[10 + 8 * k..18 + 8 * k].copy_from_slice(&(*c as u64).to_le_bytes());
+    }
+}
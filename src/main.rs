@@ -1,9 +1,18 @@
+mod config;
 mod generator;
 mod inull;
+mod stats;
+mod validate;
 
 #[cfg(feature = "redis")]
 mod iredis;
 
+#[cfg(feature = "redis")]
+mod resp;
+
+#[cfg(feature = "redis")]
+mod proxy;
+
 #[cfg(feature = "optd_original")]
 mod ioptdorig;
 
@@ -16,15 +25,30 @@ mod ioptdmem;
 #[cfg(feature = "calcite")]
 mod icalcite;
 
-use crate::generator::RawMemo;
+#[cfg(feature = "sqlite")]
+mod isqlite;
+
+#[cfg(feature = "duckdb")]
+mod iduckdb;
+
+#[cfg(any(feature = "redb", feature = "sled", feature = "rocksdb", feature = "lmdb"))]
+mod ikv;
+
+#[cfg(feature = "sql")]
+mod fromsql;
+
+use crate::config::{GeneratorConfig, RawMemoBuilder};
+use crate::generator::{Generator, RawMemo};
 use crate::inull::BenchNull;
+use crate::stats::MemoStats;
 
-use clap::{arg, Parser, Subcommand, ValueEnum};
+use clap::{arg, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use hdrhistogram::Histogram;
-use log::info;
+use log::{info, warn};
 use log::LevelFilter::{Info, Warn};
-use rand::{random, SeedableRng};
+use rand::{random, Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
 use std::io::stdout;
@@ -37,18 +61,22 @@ enum ShuffleStrategy { None, Lookup, Merge }
 
 #[derive(Parser)]
 struct Cli {
-    /// Number of groups
-    #[arg(long, short = 'g', default_value_t = 10)]
-    groups: usize,
-
-    /// Average number of expressions per group
-    #[arg(long, short = 'e', default_value_t = 1)]
-    exprs: usize,
-
     /// Use a fixed seed for random number generation
     #[arg(long, short = 'S')]
     seed: Option<u64>,
 
+    /// Load generator options from a TOML file, overridden by those given explicitly
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    profile: Option<String>,
+
+    #[command(flatten)]
+    generator: GeneratorConfig,
+
+    /// Build the initial memo from SQL in a file or from a TPC-H query with tpch:<n> instead of generating it
+    #[cfg(feature = "sql")]
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    from_sql: Option<String>,
+
     /// Output raw data to .dot file
     #[arg(long, value_hint = clap::ValueHint::DirPath)]
     raw_dot: Option<String>,
@@ -57,10 +85,6 @@ struct Cli {
     #[arg(long, value_hint = clap::ValueHint::DirPath)]
     raw_csv: Option<String>,
 
-    /// Generate a DAG instead of a tree
-    #[arg(long, short = 'd')]
-    dag: bool,
-
     /// Run all workloads
     #[arg(long, short = 'A')]
     all: bool,
@@ -77,6 +101,27 @@ struct Cli {
     #[arg(long = "match", short = 'm')]
     match_rule: bool,
 
+    /// Run physical winner lookup workload
+    #[arg(long, short = 'w')]
+    winners: bool,
+
+    /// Check the structure of the generated and shuffled memo (always done in debug builds)
+    #[arg(long)]
+    validate: bool,
+
+    /// Threads for generating blocks in parallel (0 for all cores)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Stream groups into the memo as they are generated, without keeping the whole memo in
+    /// memory (only for generated memos without passes)
+    #[arg(long)]
+    stream: bool,
+
+    /// Canonicalize the children of commutative operators when adding to the memo
+    #[arg(long)]
+    canonicalize: bool,
+
     /// CSV summary
     #[arg(long = "csv", short = 'c')]
     csv: bool,
@@ -111,19 +156,112 @@ enum BenchTypes {
     /// Redis/Valkey benchmark
     #[cfg(feature = "redis")]
     Redis {
-        /// Database connection URL
+        /// Database connection URL, also redis+unix:///path/to/socket for a Unix domain socket
         #[arg(long, short = 'D', default_value = "redis://127.0.0.1/")]
         database: String,
+        /// Match rules in the client, in the server with a script, or both
+        #[arg(long, default_value = "client")]
+        match_mode: crate::iredis::MatchMode,
+        /// Insert each group with one command per expression, a pipeline, a transaction or a script
+        #[arg(long, default_value = "per-command")]
+        insert: crate::iredis::InsertMode,
+        /// Encoding of expressions, used both as keys and as values
+        #[arg(long, default_value = "json")]
+        encoding: crate::iredis::Encoding,
+        /// Connections opened to the server and used in turn
+        #[arg(long, default_value_t = 1)]
+        connections: usize,
+        /// Start an in-process server and use it instead, with the options of the database URL
+        #[arg(long)]
+        embedded: bool,
+        #[command(flatten)]
+        network: crate::proxy::Network,
+    },
+    /// SQLite with memobench's own schema
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        /// Database file
+        #[arg(long, short = 'D', default_value = ":memory:")]
+        database: String,
+        /// Store expressions normalized into expression and child tables, or each as a blob
+        #[arg(long, default_value = "normalized")]
+        schema: crate::isqlite::Schema,
+        /// Find duplicate expressions without an index on their hash, scanning all expressions
+        #[arg(long)]
+        no_dedup_index: bool,
+        /// Journal mode, write-ahead log or rollback journal
+        #[arg(long, default_value = "rollback")]
+        journal: crate::isqlite::Journal,
+        /// Prepared statements kept in the cache, or 0 to prepare each statement when used
+        #[arg(long, default_value_t = 16)]
+        statement_cache: usize,
+        /// Insert each group in a transaction, instead of each statement on its own
+        #[arg(long)]
+        transactions: bool,
+    },
+    /// DuckDB embedded database
+    #[cfg(feature = "duckdb")]
+    Duckdb {
+        /// Database file
+        #[arg(long, short = 'D', default_value = ":memory:")]
+        database: String,
+        /// Match rules navigating groups, with a join of the expression table with itself, or both
+        #[arg(long, default_value = "join")]
+        match_mode: crate::iduckdb::MatchMode,
+    },
+    /// Embedded key-value store (redb, sled, RocksDB or LMDB)
+    #[cfg(any(feature = "redb", feature = "sled", feature = "rocksdb", feature = "lmdb"))]
+    Kv {
+        /// Key-value store, which must be enabled as a feature
+        #[arg(long)]
+        store: crate::ikv::Store,
+        /// Directory for the files of the store, created if needed (default: memobench-<store>-<pid> in the temporary directory, removed at the end)
+        #[arg(long, short = 'D')]
+        path: Option<std::path::PathBuf>,
+        /// Sync writes to disk at the end of each group, or only when the store does it on its own
+        #[arg(long, default_value = "none")]
+        durability: crate::ikv::Durability,
     },
     /// Apache Calcite
     #[cfg(feature = "calcite")]
     Calcite,
+    /// Print statistics about the structure of the memo instead of benchmarking
+    Inspect {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 pub trait Benchmark {
     fn add(&mut self, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>>;
     fn retrieve(&mut self, rng: ChaCha8Rng, memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>>;
     fn match_rules(&mut self) -> Result<Histogram<u64>, Box<dyn Error>>;
+
+    /// Look up the best physical expression of groups for required properties
+    fn winners(&mut self, _rng: ChaCha8Rng, _memo: &RawMemo) -> Result<Histogram<u64>, Box<dyn Error>> {
+        let hist =
+            Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+        warn!("physical expressions not supported");
+
+        Ok(hist)
+    }
+
+    /// Add groups as they are generated, without the whole memo in memory
+    fn add_stream(&mut self, _groups: &mut Generator) -> Result<Histogram<u64>, Box<dyn Error>> {
+        Err("streaming not supported".into())
+    }
+
+    /// Payloads of the expressions in a group, as stored, including duplicates
+    fn payloads(&mut self, _g: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        Err("payload lookup not supported".into())
+    }
+
+    /// Whether two groups ended up as the same group of the memo, e.g., once merged
+    fn same_group(&mut self, g: usize, h: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(g == h)
+    }
 }
 
 fn main() {
@@ -133,42 +271,97 @@ fn main() {
         .parse_default_env()
         .init();
 
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.profile {
+        args.generator = args.generator.with_profile(path, &matches).expect("error while loading profile");
+    }
 
     let seed = match args.seed {
         Some(s) => s,
         None => random(),
     };
     info!("repeat this run with --seed {}", seed);
-
-    let mut benchmark: Box<dyn Benchmark> = match args.benchtype {
+    info!("generator: {}", args.generator.summary());
+
+    let mut inspect = None;
+    // network conditions injected by the proxy, at the end of the CSV row
+    #[allow(unused_mut)]
+    let mut injected = String::new();
+    // memos from SQL have projections and aggregates, which not all backends can store
+    #[cfg(feature = "sql")]
+    #[allow(unused_variables)]
+    let sql_memo = args.from_sql.is_some();
+    #[cfg(not(feature = "sql"))]
+    #[allow(unused_variables)]
+    let sql_memo = false;
+    let mut benchmark: Box<dyn Benchmark> = match args.benchtype.take() {
         None => Box::new(BenchNull::new().unwrap()),
 
+        Some(BenchTypes::Inspect { json }) => {
+            inspect = Some(json);
+            Box::new(BenchNull::new().unwrap())
+        }
+
         #[cfg(feature = "optd_mem")]
-        Some(BenchTypes::OptdMem) => Box::new(crate::ioptdmem::BenchOptdMem::new().unwrap()),
+        Some(BenchTypes::OptdMem) => Box::new(crate::ioptdmem::BenchOptdMem::new(args.canonicalize).unwrap()),
 
         #[cfg(feature = "optd_db")]
-        Some(BenchTypes::OptdDb { database }) => Box::new(crate::ioptddb::BenchOptdDb::new(&database).unwrap()),
+        Some(BenchTypes::OptdDb { database }) => {
+            assert!(!sql_memo, "optd-db has no project or aggregate operators, use another backend with --from-sql");
+            Box::new(crate::ioptddb::BenchOptdDb::new(&database, args.canonicalize).unwrap())
+        }
 
         #[cfg(feature = "optd_original")]
-        Some(BenchTypes::OptdOrig) => Box::new(crate::ioptdorig::BenchOptdOriginal::new().unwrap()),
+        Some(BenchTypes::OptdOrig) => Box::new(crate::ioptdorig::BenchOptdOriginal::new(args.canonicalize).unwrap()),
 
         #[cfg(feature = "redis")]
-        Some(BenchTypes::Redis { database }) => Box::new(crate::iredis::BenchRedis::new(database).unwrap()),
+        Some(BenchTypes::Redis { database, match_mode, insert, encoding, connections, embedded, network }) => {
+            let database = if embedded { crate::resp::start(&database).unwrap() } else { database };
+            let database = if network.is_set() { crate::iredis::proxied(&database, &network).unwrap() } else { database };
+            injected = network.csv();
+            Box::new(crate::iredis::BenchRedis::new(database, args.canonicalize, match_mode, insert, encoding, connections).unwrap())
+        }
+
+        #[cfg(feature = "sqlite")]
+        Some(BenchTypes::Sqlite { database, schema, no_dedup_index, journal, statement_cache, transactions }) =>
+            Box::new(crate::isqlite::BenchSqlite::new(&database, args.canonicalize, schema, !no_dedup_index, journal, statement_cache, transactions).unwrap()),
+
+        #[cfg(feature = "duckdb")]
+        Some(BenchTypes::Duckdb { database, match_mode }) => Box::new(crate::iduckdb::BenchDuckDb::new(&database, args.canonicalize, match_mode).unwrap()),
+
+        #[cfg(any(feature = "redb", feature = "sled", feature = "rocksdb", feature = "lmdb"))]
+        Some(BenchTypes::Kv { store, path, durability }) => Box::new(crate::ikv::BenchKv::new(store, path, durability, args.canonicalize).unwrap()),
 
         #[cfg(feature = "calcite")]
-        Some(BenchTypes::Calcite) => Box::new(crate::icalcite::BenchCalcite::new().unwrap()),
+        Some(BenchTypes::Calcite) => {
+            assert!(!sql_memo, "calcite has no project or aggregate operators, use another backend with --from-sql");
+            Box::new(crate::icalcite::BenchCalcite::new(args.canonicalize).unwrap())
+        }
     };
 
-    let memo = RawMemo::new(
-        args.groups,
-        args.exprs,
-        args.dag,
-        ChaCha8Rng::seed_from_u64(seed),
-    );
+    if args.stream && inspect.is_none() {
+        run_stream(benchmark.as_mut(), &args, seed, &injected).expect("error while streaming memo");
+        return;
+    }
+
+    #[cfg(feature = "sql")]
+    let from_sql = args.from_sql.as_ref().map(|spec| RawMemo::from_sql(spec).expect("error while building memo from SQL"));
+    #[cfg(not(feature = "sql"))]
+    let from_sql = None;
+
+    let builder = RawMemoBuilder::from(args.generator.clone()).threads(args.threads);
+    let mut memo = match from_sql {
+        Some(memo) => builder.build_from(memo, seed),
+        None => builder.build(seed),
+    }.expect("error while generating memo");
+
+    if args.canonicalize {
+        memo.merge_commuted();
+    }
 
     if args.csv {
-        print!("{},{},{}", args.groups, args.exprs, memo.len());
+        print!("{},{},{}", args.generator.groups, args.generator.exprs, memo.len());
     }
 
     if let Some(path) = args.raw_dot {
@@ -179,18 +372,25 @@ fn main() {
         memo.dump_dot(&mut writer).unwrap();
     }
 
+    let deferred = memo.defer_cycles();
+
     let shuffled = match args.shuffle {
         ShuffleStrategy::None => {
-            memo.clone()
+            deferred
         }
         ShuffleStrategy::Lookup => {
-            memo.shuffle(args.chunk, false)
+            deferred.shuffle(args.chunk, false)
         }
         ShuffleStrategy::Merge => {
-            memo.shuffle(args.chunk, true)
+            deferred.shuffle(args.chunk, true)
         }
     };
 
+    if args.validate || cfg!(debug_assertions) {
+        memo.validate().expect("invalid memo");
+        shuffled.validate_shuffled(&memo).expect("invalid shuffled memo");
+    }
+
     if let Some(path) = args.raw_csv {
         let mut writer = match &path[..] {
             "-" => Box::new(stdout()),
@@ -199,10 +399,23 @@ fn main() {
         shuffled.dump_csv(&mut writer).unwrap();
     }
 
+    if let Some(json) = inspect {
+        let stats = MemoStats::new(&memo);
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+            false => stats.print(),
+        }
+        return;
+    }
+
     if args.add || args.all {
         let now = Instant::now();
         let hist = benchmark.add(&shuffled).expect("error while running add test");
         log_summary(hist, "add", now.elapsed(), args.csv);
+
+        if !memo.commuted.is_empty() {
+            check_commuted(benchmark.as_mut(), &memo, args.canonicalize);
+        }
     }
 
     if args.retrieve || args.all {
@@ -221,9 +434,147 @@ fn main() {
         log_summary(hist, "match", now.elapsed(), args.csv);
     }
 
+    if args.winners || (args.all && args.generator.physical) {
+        let now = Instant::now();
+        let hist = benchmark
+            .winners(ChaCha8Rng::seed_from_u64(seed + 1000), &memo)
+            .expect("error while runnning winners test");
+        log_summary(hist, "winners", now.elapsed(), args.csv);
+    }
+
     if args.csv {
-        println!("");
+        println!("{}", injected);
+    }
+}
+
+/// Run workloads over a memo streamed from the generator, regenerating it from the seed
+/// whenever it is needed again.
+fn run_stream(benchmark: &mut dyn Benchmark, args: &Cli, seed: u64, injected: &str) -> Result<(), Box<dyn Error>> {
+    let config = &args.generator;
+    #[cfg(feature = "sql")]
+    let from_sql = args.from_sql.is_some();
+    #[cfg(not(feature = "sql"))]
+    let from_sql = false;
+    if from_sql || config.join_graph.is_some() || config.physical || config.scalars || config.payload > 0
+        || config.cycles > 0 || config.duplicates > 0 || config.commute > 0 || config.blocks > 1
+        || args.shuffle != ShuffleStrategy::None || args.raw_dot.is_some() || args.raw_csv.is_some() || args.winners {
+        return Err("streaming only supports generated memos without passes, shuffling or raw output".into());
+    }
+    config.validate()?;
+
+    info!("target: {} groups, {} expressions/group, {} roots", config.groups, config.exprs, config.roots);
+
+    // a first pass only counts, to pick random groups to retrieve
+    let mut ngroups = 0;
+    if args.csv || args.retrieve || args.all {
+        let mut generator = Generator::new(config, ChaCha8Rng::seed_from_u64(seed));
+        ngroups = generator.by_ref().count();
+        let (_, nexprs) = generator.len();
+        info!("result: {} groups, {} expressions, {} roots, depth {}", ngroups, nexprs, generator.entries().len(), generator.depth());
+        if args.csv {
+            print!("{},{},{}", config.groups, config.exprs, nexprs);
+        }
+    }
+
+    if args.add || args.all {
+        let mut generator = Generator::new(config, ChaCha8Rng::seed_from_u64(seed));
+        let now = Instant::now();
+        let hist = benchmark.add_stream(&mut generator)?;
+        log_summary(hist, "add", now.elapsed(), args.csv);
+    }
+
+    if args.retrieve || args.all {
+        let now = Instant::now();
+        let hist = retrieve_stream(benchmark, config, seed, ngroups)?;
+        log_summary(hist, "retrieve", now.elapsed(), args.csv);
+    }
+
+    if args.match_rule || args.all {
+        let now = Instant::now();
+        let hist = benchmark.match_rules()?;
+        log_summary(hist, "match", now.elapsed(), args.csv);
+    }
+
+    if args.csv {
+        println!("{}", injected);
+    }
+
+    Ok(())
+}
+
+/// Retrieve all groups in order and then random ones, checking their payloads against
+/// groups generated again from the seed. Only the expected payloads of random groups are kept.
+fn retrieve_stream(benchmark: &mut dyn Benchmark, config: &GeneratorConfig, seed: u64, ngroups: usize) -> Result<Histogram<u64>, Box<dyn Error>> {
+    let mut hist =
+        Histogram::<u64>::new_with_bounds(1, Duration::from_secs(1).as_nanos() as u64, 2)?;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed + 1000);
+    let random: Vec<usize> = (0..1000).map(|_| rng.gen_range(0..ngroups)).collect();
+    let wanted: HashSet<usize> = random.iter().cloned().collect();
+    let mut expected = HashMap::new();
+
+    for g in Generator::new(config, ChaCha8Rng::seed_from_u64(seed)) {
+        let mut payloads: Vec<usize> = g.exprs.iter().map(|(_, e)| e.data).collect();
+        payloads.sort();
+        lookup_payloads(benchmark, &mut hist, g.id, &payloads)?;
+        if wanted.contains(&g.id) {
+            expected.insert(g.id, payloads);
+        }
+    }
+    for g in random {
+        lookup_payloads(benchmark, &mut hist, g, &expected[&g])?;
+    }
+
+    Ok(hist)
+}
+
+fn lookup_payloads(benchmark: &mut dyn Benchmark, hist: &mut Histogram<u64>, g: usize, expected: &Vec<usize>) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+
+    let mut ids = benchmark.payloads(g)?;
+
+    if let Err(_) = hist.record(start.elapsed().as_nanos() as u64) {
+        warn!("histogram overflow")
+    }
+
+    ids.sort();
+    ids.dedup();
+    assert_eq!(&ids, expected, "incorrect memo");
+
+    Ok(())
+}
+
+/// Report whether the memo treats joins with swapped children as duplicates, i.e., stores
+/// the payload of each pair only once.
+fn check_commuted(benchmark: &mut dyn Benchmark, memo: &RawMemo, canonicalize: bool) {
+    let mut duplicates = 0;
+    for (j, copy) in memo.commuted.iter() {
+        let (g, h) = (memo.group_of(*j), memo.group_of(*copy));
+        // merged groups are the same group of the memo, so its payloads are counted once
+        let groups = match benchmark.same_group(g, h) {
+            Ok(true) => vec![g],
+            Ok(false) => vec![g, h],
+            Err(e) => {
+                warn!("cannot check commuted joins: {}", e);
+                return;
+            }
+        };
+        let mut payloads = vec![];
+        for group in groups {
+            match benchmark.payloads(group) {
+                Ok(p) => payloads.extend(p),
+                Err(e) => {
+                    warn!("cannot check commuted joins: {}", e);
+                    return;
+                }
+            }
+        }
+        if payloads.iter().filter(|p| **p == memo.exprs[*j].data).count() == 1 {
+            duplicates += 1;
+        }
     }
+    info!("commute: {} of {} pairs treated as duplicates (canonicalize={})",
+        duplicates, memo.commuted.len(), canonicalize);
 }
 
 fn log_summary(hist: Histogram<u64>, workload: &str, tot: Duration, csv: bool) {
@@ -0,0 +1,171 @@
+//! TCP proxy that injects latency, jitter and limited bandwidth between a benchmark and its
+//! server, to model a remote memo store on a single machine.
+
+use clap::Args;
+use log::{debug, info, warn};
+use rand::Rng;
+use std::cmp::max;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Network conditions injected by the proxy, in each direction.
+#[derive(Args, Clone)]
+pub struct Network {
+    /// Start a proxy to the server that delays data in each direction (e.g., 200us, 1ms)
+    #[arg(long, value_parser = parse_duration)]
+    pub inject_latency: Option<Duration>,
+    /// Maximum random delay added to the latency, without reordering data
+    #[arg(long, value_parser = parse_duration)]
+    pub jitter: Option<Duration>,
+    /// Bandwidth of each direction, in bits or bytes per second (e.g., 1Gbit, 100Mbit, 10MB)
+    #[arg(long, value_parser = parse_bandwidth)]
+    pub bandwidth: Option<u64>,
+}
+
+impl Network {
+    pub fn is_set(&self) -> bool {
+        self.inject_latency.is_some() || self.jitter.is_some() || self.bandwidth.is_some()
+    }
+
+    /// Latency and jitter in nanoseconds and bandwidth in bits per second (0 for unlimited), as
+    /// columns to append to a CSV row.
+    pub fn csv(&self) -> String {
+        format!(",{},{},{}", self.inject_latency.unwrap_or_default().as_nanos(),
+            self.jitter.unwrap_or_default().as_nanos(), self.bandwidth.unwrap_or(0))
+    }
+}
+
+/// Start the proxy on an ephemeral port of localhost, forwarding each connection to the target.
+pub fn start(target: String, network: &Network) -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    info!("proxy: {} to {}, latency = {:?}, jitter = {:?}, bandwidth = {}", addr, target,
+        network.inject_latency.unwrap_or_default(), network.jitter.unwrap_or_default(),
+        network.bandwidth.map_or("unlimited".to_string(), |b| format!("{} bit/s", b)));
+
+    let network = network.clone();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let connected = client.and_then(|client| Ok((client, TcpStream::connect(&target)?)));
+            match connected {
+                Ok((client, server)) => {
+                    for (from, to) in [(client.try_clone(), server.try_clone()), (Ok(server), Ok(client))] {
+                        let network = network.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = from.and_then(|from| forward(from, to?, &network)) {
+                                debug!("proxy: {}", e);
+                            }
+                        });
+                    }
+                }
+                Err(e) => warn!("proxy: {}", e),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Forward data from one socket to the other, delivering each chunk read as if it was sent over
+/// a link with the given latency, jitter and bandwidth.
+fn forward(mut from: TcpStream, mut to: TcpStream, network: &Network) -> io::Result<()> {
+    from.set_nodelay(true)?;
+    to.set_nodelay(true)?;
+
+    let (tx, rx) = mpsc::channel::<(Instant, Vec<u8>)>();
+    let writer = thread::spawn(move || -> io::Result<()> {
+        for (due, data) in rx {
+            wait_until(due);
+            to.write_all(&data)?;
+        }
+        to.shutdown(Shutdown::Write)
+    });
+
+    let latency = network.inject_latency.unwrap_or_default();
+    let mut rng = rand::thread_rng();
+    let mut link_free = Instant::now(); // when the previous chunk has been transmitted
+    let mut last_due = Instant::now();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = from.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        let mut sent = Instant::now();
+        if let Some(bandwidth) = network.bandwidth {
+            link_free = max(link_free, sent) + Duration::from_nanos(n as u64 * 8 * 1_000_000_000 / bandwidth);
+            sent = link_free;
+        }
+        let jitter = network.jitter.map_or(Duration::ZERO, |j| j.mul_f64(rng.gen()));
+
+        // as in a TCP stream, jitter does not reorder data
+        last_due = max(last_due, sent + latency + jitter);
+        if tx.send((last_due, buf[..n].to_vec())).is_err() {
+            break;
+        }
+    }
+
+    drop(tx);
+    writer.join().unwrap()
+}
+
+fn wait_until(due: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= due {
+            return;
+        }
+        // sleep overshoots by tens of microseconds, so the end is spent yielding
+        let left = due - now;
+        if left > Duration::from_micros(100) {
+            thread::sleep(left - Duration::from_micros(100));
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Parse a duration with a unit, such as 200us or 1.5ms.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = split_unit(s)?;
+    let scale = match unit {
+        "ns" => 1e-9,
+        "us" | "µs" => 1e-6,
+        "ms" => 1e-3,
+        "s" => 1.0,
+        _ => return Err(format!("unknown unit of time '{}' (use ns, us, ms or s)", unit)),
+    };
+    Duration::try_from_secs_f64(value * scale).map_err(|e| e.to_string())
+}
+
+/// Parse a bandwidth in bits or bytes per second, such as 1Gbit or 10MB, into bits per second.
+pub fn parse_bandwidth(s: &str) -> Result<u64, String> {
+    let (value, unit) = split_unit(s.trim_end_matches("/s"))?;
+    let scale = match unit {
+        "bit" => 1.0,
+        "Kbit" | "kbit" => 1e3,
+        "Mbit" => 1e6,
+        "Gbit" => 1e9,
+        "B" => 8.0,
+        "KB" | "kB" => 8e3,
+        "MB" => 8e6,
+        "GB" => 8e9,
+        _ => return Err(format!("unknown unit of bandwidth '{}' (use bit, Kbit, Mbit, Gbit, B, KB, MB or GB)", unit)),
+    };
+    match (value * scale) as u64 {
+        0 => Err("bandwidth must be positive".to_string()),
+        b => Ok(b),
+    }
+}
+
+fn split_unit(s: &str) -> Result<(f64, &str), String> {
+    let i = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let value = s[..i].parse::<f64>().map_err(|_| format!("invalid number in '{}'", s))?;
+    Ok((value, &s[i..]))
+}
@@ -0,0 +1,532 @@
+//! Minimal in-process server of the Redis protocol (RESP2, and RESP3 after HELLO 3), with only
+//! the commands used by the redis benchmark, so that it can run without an external server.
+
+use crate::iredis::{INSERT_SCRIPT, MATCH_SCRIPT};
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Start the server on an ephemeral port of localhost and return the URL to connect to it,
+/// keeping the options (e.g., ?protocol=resp3) of the given URL.
+pub fn start(database: &str) -> Result<String, Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let db = Arc::new(Mutex::new(Db::default()));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let db = db.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, db) {
+                            warn!("embedded redis: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("embedded redis: {}", e),
+            }
+        }
+    });
+
+    info!("redis: embedded server on {}", addr);
+    Ok(match database.split_once('?') {
+        Some((_, options)) => format!("redis://{}/?{}", addr, options),
+        None => format!("redis://{}/", addr),
+    })
+}
+
+/// Fields and values of a hash, in order so that replies are deterministic.
+type Fields = BTreeMap<Vec<u8>, Vec<u8>>;
+
+enum Entry {
+    Str(Vec<u8>),
+    Hash(Fields),
+    Set(BTreeSet<Vec<u8>>),
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+    Set(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(msg: impl Into<String>) -> Reply {
+        Reply::Error(msg.into())
+    }
+
+    fn bulk(s: &str) -> Reply {
+        Reply::Bulk(s.as_bytes().to_vec())
+    }
+
+    fn write(&self, out: &mut impl Write, resp3: bool) -> io::Result<()> {
+        match self {
+            Reply::Status(s) => write!(out, "+{}\r\n", s),
+            Reply::Error(e) => write!(out, "-{}\r\n", e),
+            Reply::Int(i) => write!(out, ":{}\r\n", i),
+            Reply::Bulk(b) => {
+                write!(out, "${}\r\n", b.len())?;
+                out.write_all(b)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Nil if resp3 => write!(out, "_\r\n"),
+            Reply::Nil => write!(out, "$-1\r\n"),
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|r| r.write(out, resp3))
+            }
+            // maps and sets are flattened into arrays in RESP2
+            Reply::Map(pairs) => {
+                if resp3 {
+                    write!(out, "%{}\r\n", pairs.len())?;
+                } else {
+                    write!(out, "*{}\r\n", pairs.len() * 2)?;
+                }
+                pairs.iter().try_for_each(|(k, v)| {
+                    k.write(out, resp3)?;
+                    v.write(out, resp3)
+                })
+            }
+            Reply::Set(items) => {
+                write!(out, "{}{}\r\n", if resp3 { '~' } else { '*' }, items.len())?;
+                items.iter().try_for_each(|r| r.write(out, resp3))
+            }
+        }
+    }
+}
+
+/// Serve a connection, with its own protocol version and transaction.
+fn serve(stream: TcpStream, db: Arc<Mutex<Db>>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut resp3 = false;
+    let mut multi: Option<Vec<Vec<Vec<u8>>>> = None;
+
+    while let Some(args) = read_command(&mut reader)? {
+        if args.is_empty() {
+            continue;
+        }
+        let reply = match upper(&args[0]).as_str() {
+            "HELLO" => match args.get(1).map(|v| v.as_slice()) {
+                None | Some(b"2") | Some(b"3") => {
+                    if let Some(v) = args.get(1) {
+                        resp3 = v == b"3";
+                    }
+                    Reply::Map(vec![
+                        (Reply::bulk("server"), Reply::bulk("redis")),
+                        (Reply::bulk("version"), Reply::bulk("7.0.0")),
+                        (Reply::bulk("proto"), Reply::Int(if resp3 { 3 } else { 2 })),
+                        (Reply::bulk("mode"), Reply::bulk("standalone")),
+                        (Reply::bulk("role"), Reply::bulk("master")),
+                        (Reply::bulk("modules"), Reply::Array(vec![])),
+                    ])
+                }
+                Some(_) => Reply::error("NOPROTO unsupported protocol version"),
+            },
+            "MULTI" if multi.is_some() => Reply::error("ERR MULTI calls can not be nested"),
+            "MULTI" => {
+                multi = Some(vec![]);
+                Reply::Status("OK")
+            }
+            "EXEC" => match multi.take() {
+                None => Reply::error("ERR EXEC without MULTI"),
+                Some(queued) => {
+                    // atomic, as the lock is held for all commands
+                    let mut db = db.lock().unwrap();
+                    Reply::Array(queued.iter().map(|args| db.command(args)).collect())
+                }
+            },
+            "DISCARD" => match multi.take() {
+                None => Reply::error("ERR DISCARD without MULTI"),
+                Some(_) => Reply::Status("OK"),
+            },
+            _ => match multi.as_mut() {
+                Some(queued) => {
+                    queued.push(args);
+                    Reply::Status("QUEUED")
+                }
+                None => db.lock().unwrap().command(&args),
+            },
+        };
+
+        reply.write(&mut writer, resp3)?;
+        // replies to a pipeline are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a command, either as an array of bulk strings or inline, or None at the end.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut line = vec![];
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    let line = line.strip_suffix(b"\r\n").unwrap_or(&line);
+
+    if line.first() != Some(&b'*') {
+        return Ok(Some(line.split(|b| b.is_ascii_whitespace())
+            .filter(|a| !a.is_empty())
+            .map(|a| a.to_vec())
+            .collect()));
+    }
+
+    let n = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(n);
+    for _ in 0..n {
+        let mut line = vec![];
+        reader.read_until(b'\n', &mut line)?;
+        if line.first() != Some(&b'$') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a bulk string"));
+        }
+        let len = parse_len(line[1..].strip_suffix(b"\r\n").unwrap_or(&line[1..]))?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn parse_len(s: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(s).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid length"))
+}
+
+fn upper(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).to_uppercase()
+}
+
+#[derive(Default)]
+struct Db {
+    keys: HashMap<Vec<u8>, Entry>,
+    scripts: HashMap<String, String>, // by SHA1, as in EVALSHA
+}
+
+impl Db {
+    fn command(&mut self, args: &[Vec<u8>]) -> Reply {
+        match self.run(args) {
+            Ok(reply) => reply,
+            Err(reply) => reply,
+        }
+    }
+
+    fn run(&mut self, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let name = upper(&args[0]);
+        let wrong_args = || Reply::error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()));
+        let arity = |min: usize| if args.len() < min { Err(wrong_args()) } else { Ok(()) };
+
+        match name.as_str() {
+            "PING" => Ok(Reply::Status("PONG")),
+            "CLIENT" | "SELECT" => Ok(Reply::Status("OK")),
+            "FLUSHDB" | "FLUSHALL" => {
+                self.keys.clear();
+                Ok(Reply::Status("OK"))
+            }
+            "DBSIZE" => Ok(Reply::Int(self.keys.len() as i64)),
+            "EXISTS" => {
+                arity(2)?;
+                Ok(Reply::Int(args[1..].iter().filter(|k| self.keys.contains_key(*k)).count() as i64))
+            }
+            "DEL" => {
+                arity(2)?;
+                Ok(Reply::Int(args[1..].iter().filter(|k| self.keys.remove(*k).is_some()).count() as i64))
+            }
+            "GET" => {
+                arity(2)?;
+                Ok(self.get(&args[1])?.map_or(Reply::Nil, Reply::Bulk))
+            }
+            "SET" => {
+                arity(3)?;
+                let (mut nx, mut xx, mut get) = (false, false, false);
+                for opt in args[3..].iter() {
+                    match upper(opt).as_str() {
+                        "NX" => nx = true,
+                        "XX" => xx = true,
+                        "GET" => get = true,
+                        _ => return Err(Reply::error("ERR syntax error")),
+                    }
+                }
+                let old = if get { self.get(&args[1])? } else { None };
+                let exists = self.keys.contains_key(&args[1]);
+                let set = if exists { !nx } else { !xx };
+                if set {
+                    self.keys.insert(args[1].clone(), Entry::Str(args[2].clone()));
+                }
+                Ok(match (get, set) {
+                    (true, _) => old.map_or(Reply::Nil, Reply::Bulk),
+                    (false, true) => Reply::Status("OK"),
+                    (false, false) => Reply::Nil,
+                })
+            }
+            "HSET" => {
+                arity(4)?;
+                if !args.len().is_multiple_of(2) {
+                    return Err(wrong_args());
+                }
+                let hash = self.hash_mut(&args[1])?;
+                let added = args[2..].chunks(2).filter(|fv| hash.insert(fv[0].clone(), fv[1].clone()).is_none()).count();
+                Ok(Reply::Int(added as i64))
+            }
+            "HGET" => {
+                arity(3)?;
+                Ok(self.hash(&args[1])?.and_then(|h| h.get(&args[2]).cloned()).map_or(Reply::Nil, Reply::Bulk))
+            }
+            "HLEN" => {
+                arity(2)?;
+                Ok(Reply::Int(self.hash(&args[1])?.map_or(0, |h| h.len()) as i64))
+            }
+            "HGETALL" => {
+                arity(2)?;
+                Ok(Reply::Map(self.hash(&args[1])?.into_iter().flatten()
+                    .map(|(f, v)| (Reply::Bulk(f.clone()), Reply::Bulk(v.clone())))
+                    .collect()))
+            }
+            "SADD" => {
+                arity(3)?;
+                let set = self.set_mut(&args[1])?;
+                Ok(Reply::Int(args[2..].iter().filter(|m| set.insert(m.to_vec())).count() as i64))
+            }
+            "SREM" => {
+                arity(3)?;
+                let set = self.set_mut(&args[1])?;
+                let removed = args[2..].iter().filter(|m| set.remove(*m)).count();
+                if set.is_empty() {
+                    self.keys.remove(&args[1]);
+                }
+                Ok(Reply::Int(removed as i64))
+            }
+            "SMEMBERS" => {
+                arity(2)?;
+                Ok(Reply::Set(self.set(&args[1])?.into_iter().flatten().map(|m| Reply::Bulk(m.clone())).collect()))
+            }
+            "SCRIPT" => {
+                arity(2)?;
+                match upper(&args[1]).as_str() {
+                    "LOAD" => {
+                        arity(3)?;
+                        let source = String::from_utf8_lossy(&args[2]).to_string();
+                        let sha = redis::Script::new(&source).get_hash().to_string();
+                        self.scripts.insert(sha.clone(), source);
+                        Ok(Reply::Bulk(sha.into_bytes()))
+                    }
+                    "EXISTS" => Ok(Reply::Array(args[2..].iter()
+                        .map(|sha| Reply::Int(self.scripts.contains_key(&*String::from_utf8_lossy(sha)) as i64))
+                        .collect())),
+                    "FLUSH" => {
+                        self.scripts.clear();
+                        Ok(Reply::Status("OK"))
+                    }
+                    _ => Err(Reply::error("ERR unknown SCRIPT subcommand")),
+                }
+            }
+            "EVAL" | "EVALSHA" => {
+                arity(3)?;
+                let source = if name == "EVAL" {
+                    String::from_utf8_lossy(&args[1]).to_string()
+                } else {
+                    self.scripts.get(&*String::from_utf8_lossy(&args[1])).cloned()
+                        .ok_or_else(|| Reply::error("NOSCRIPT No matching script. Please use EVAL."))?
+                };
+                let nkeys = parse_len(&args[2]).ok().filter(|n| 3 + n <= args.len())
+                    .ok_or_else(|| Reply::error("ERR Number of keys can't be greater than number of args"))?;
+                let (keys, argv) = args[3..].split_at(nkeys);
+                // stubs of the scripts of the benchmark, as there is no Lua here
+                if source == INSERT_SCRIPT {
+                    self.insert_script(keys, argv)
+                } else if source == MATCH_SCRIPT {
+                    self.match_script(argv)
+                } else {
+                    Err(Reply::error("ERR only the scripts of the benchmark are supported"))
+                }
+            }
+            _ => Err(Reply::error(format!("ERR unknown command '{}'", String::from_utf8_lossy(&args[0])))),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Reply> {
+        match self.keys.get(key) {
+            None => Ok(None),
+            Some(Entry::Str(v)) => Ok(Some(v.clone())),
+            Some(_) => Err(Reply::error(WRONGTYPE)),
+        }
+    }
+
+    fn hash(&self, key: &[u8]) -> Result<Option<&Fields>, Reply> {
+        match self.keys.get(key) {
+            None => Ok(None),
+            Some(Entry::Hash(h)) => Ok(Some(h)),
+            Some(_) => Err(Reply::error(WRONGTYPE)),
+        }
+    }
+
+    fn hash_mut(&mut self, key: &[u8]) -> Result<&mut Fields, Reply> {
+        match self.keys.entry(key.to_vec()).or_insert_with(|| Entry::Hash(BTreeMap::new())) {
+            Entry::Hash(h) => Ok(h),
+            _ => Err(Reply::error(WRONGTYPE)),
+        }
+    }
+
+    fn set(&self, key: &[u8]) -> Result<Option<&BTreeSet<Vec<u8>>>, Reply> {
+        match self.keys.get(key) {
+            None => Ok(None),
+            Some(Entry::Set(s)) => Ok(Some(s)),
+            Some(_) => Err(Reply::error(WRONGTYPE)),
+        }
+    }
+
+    fn set_mut(&mut self, key: &[u8]) -> Result<&mut BTreeSet<Vec<u8>>, Reply> {
+        match self.keys.entry(key.to_vec()).or_insert_with(|| Entry::Set(BTreeSet::new())) {
+            Entry::Set(s) => Ok(s),
+            _ => Err(Reply::error(WRONGTYPE)),
+        }
+    }
+
+    /// Current group of a group id, as find in the insert script.
+    fn find(&self, mut gid: Vec<u8>) -> Result<Vec<u8>, Reply> {
+        while let Some(next) = self.hash(b"fwd")?.and_then(|h| h.get(&gid)) {
+            gid = next.clone();
+        }
+        Ok(gid)
+    }
+
+    /// Same as INSERT_SCRIPT.
+    fn insert_script(&mut self, keys: &[Vec<u8>], argv: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let mut gid = argv.last().ok_or_else(|| Reply::error("ERR missing group id"))?.clone();
+        let mut owned = false;
+        let mut forwarded = 0;
+        let mut others = vec![];
+        let mut fresh = vec![];
+        for (j, key) in keys.iter().enumerate() {
+            let id = self.get(key)?;
+            match id {
+                Some(id) => {
+                    let id = self.find(id)?;
+                    if !owned {
+                        if id != gid {
+                            self.hash_mut(b"fwd")?.insert(gid.clone(), id.clone());
+                            forwarded = 1;
+                        }
+                        gid = id;
+                        owned = true;
+                    } else if id != gid {
+                        others.push(Reply::Bulk(id));
+                    }
+                }
+                None => {
+                    self.keys.insert(key.clone(), Entry::Str(gid.clone()));
+                    owned = true;
+                    fresh.push((argv[j].clone(), key.clone()));
+                    for c in decode(key)?["children"].as_array().into_iter().flatten() {
+                        self.set_mut(format!("parents:{}", c).as_bytes())?.insert(key.clone());
+                    }
+                }
+            }
+        }
+        if !fresh.is_empty() {
+            self.hash_mut(&gid)?.extend(fresh);
+        }
+
+        let gid = parse_len(&gid).map_err(|e| Reply::error(format!("ERR {}", e)))?;
+        Ok(Reply::Array(vec![Reply::Int(gid as i64), Reply::Int(forwarded), Reply::Array(others)]))
+    }
+
+    /// Same as MATCH_SCRIPT.
+    fn match_script(&self, argv: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let mut visited = HashSet::new();
+        let (mut explored, mut shared) = (0, 0);
+        let mut bindings = vec![];
+        for root in argv.iter() {
+            let mut root_groups = HashSet::new();
+            let mut stack = vec![root.clone()];
+            while let Some(g) = stack.pop() {
+                if visited.insert(g.clone()) {
+                    root_groups.insert(g.clone());
+                    explored += 1;
+                    for (f, v) in self.hash(&g)?.into_iter().flatten() {
+                        let e = decode(v)?;
+                        let children = e["children"].as_array().cloned().unwrap_or_default();
+                        stack.extend(children.iter().map(|c| c.to_string().into_bytes()));
+                        if e["type"] == 1 {
+                            let bot = children.first().map(|c| c.to_string().into_bytes()).unwrap_or_default();
+                            for (f2, v2) in self.hash(&bot)?.into_iter().flatten() {
+                                if decode(v2)?["type"] == 2 {
+                                    bindings.push(Reply::Bulk(f.clone()));
+                                    bindings.push(Reply::Bulk(f2.clone()));
+                                }
+                            }
+                        }
+                    }
+                } else if root_groups.insert(g) {
+                    shared += 1;
+                }
+            }
+        }
+        Ok(Reply::Array(vec![Reply::Int(explored), Reply::Int(shared), Reply::Array(bindings)]))
+    }
+}
+
+fn decode(json: &[u8]) -> Result<Value, Reply> {
+    serde_json::from_slice(json).map_err(|e| Reply::error(format!("ERR Error running script: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::start;
+    use crate::config::RawMemoBuilder;
+    use crate::iredis::{BenchRedis, Encoding, InsertMode, MatchMode};
+    use crate::Benchmark;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    /// Add, retrieve and match a memo with merged duplicates, inserting and matching in the
+    /// client and then with the scripts, which the server emulates.
+    fn run(protocol: &str) {
+        let memo = RawMemoBuilder::new().groups(200).exprs(3).dag(true).duplicates(10).build(5).unwrap();
+        let shuffled = memo.defer_cycles().shuffle(2, true);
+        let database = start(&format!("redis://127.0.0.1/?protocol={}", protocol)).unwrap();
+
+        let mut bindings = vec![];
+        for (insert, match_mode) in [(InsertMode::PerCommand, MatchMode::Client), (InsertMode::Script, MatchMode::Server)] {
+            let mut bench = BenchRedis::new(database.clone(), false, match_mode, insert, Encoding::Json, 1).unwrap();
+            bench.add(&shuffled).unwrap();
+
+            let mut con = redis::Client::open(database.as_str()).unwrap().get_connection().unwrap();
+            let merged: usize = redis::cmd("HLEN").arg("fwd").query(&mut con).unwrap();
+            assert!(merged > 0, "no groups merged");
+
+            bench.retrieve(ChaCha8Rng::seed_from_u64(6), &memo).unwrap();
+            bindings.push(bench.match_rules().unwrap().len());
+        }
+        assert!(bindings[0] > 0, "no bindings matched");
+        assert_eq!(bindings[0], bindings[1], "bindings matched in the client and on the server");
+    }
+
+    #[test]
+    fn resp2() {
+        run("resp2");
+    }
+
+    #[test]
+    fn resp3() {
+        run("resp3");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::generator::{RawMemo, OP_AGGREGATE, OP_FILTER, OP_JOIN, OP_PROJECT, OP_SCAN};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+/// Statistics about the structure of a memo, to check that it looks as intended before
+/// benchmarking it. Distributions map a value to the number of groups or expressions with it.
+#[derive(Serialize)]
+pub struct MemoStats {
+    pub groups: usize,
+    pub exprs: usize,
+    pub roots: usize,
+    pub depth: usize,
+    pub group_sizes: BTreeMap<usize, usize>, // expressions per group
+    pub operators: BTreeMap<String, usize>,
+    pub fan_out: BTreeMap<usize, usize>, // distinct child groups per group
+    pub fan_in: BTreeMap<usize, usize>, // distinct parent groups per group
+    pub shared: usize, // groups with more than one parent
+    pub reachable: usize, // from the roots
+    pub unreachable: usize,
+    pub plan_space_log10: f64, // distinct plans of all roots, without cycles
+    pub phys: usize,
+    pub scalars: usize,
+    pub cycles: usize,
+    pub merges: usize,
+    pub commuted: usize,
+}
+
+impl MemoStats {
+    pub fn new(memo: &RawMemo) -> Self {
+        let mut parents: Vec<HashSet<usize>> = vec![HashSet::new(); memo.groups.len()];
+        let mut group_sizes = BTreeMap::new();
+        let mut operators = BTreeMap::new();
+        let mut fan_out = BTreeMap::new();
+        for (g, group) in memo.groups.iter().enumerate() {
+            *group_sizes.entry(group.exprs.len()).or_insert(0) += 1;
+            let mut children = HashSet::new();
+            for e in group.exprs.iter() {
+                let expr = &memo.exprs[*e];
+                let op = match expr.op {
+                    OP_SCAN => "scan".to_string(),
+                    OP_FILTER => "filter".to_string(),
+                    OP_JOIN => "join".to_string(),
+                    OP_PROJECT => "project".to_string(),
+                    OP_AGGREGATE => "aggregate".to_string(),
+                    op => format!("op{}", op),
+                };
+                *operators.entry(op).or_insert(0) += 1;
+                for c in expr.children.iter() {
+                    children.insert(*c);
+                    parents[*c].insert(g);
+                }
+            }
+            *fan_out.entry(children.len()).or_insert(0) += 1;
+        }
+
+        let mut fan_in = BTreeMap::new();
+        for p in parents.iter() {
+            *fan_in.entry(p.len()).or_insert(0) += 1;
+        }
+
+        // groups reachable from the roots
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut stack = memo.entries.clone();
+        while let Some(g) = stack.pop() {
+            if visited.insert(g) {
+                for e in memo.groups[g].exprs.iter() {
+                    stack.extend(memo.exprs[*e].children.iter().filter(|c| !visited.contains(c)));
+                }
+            }
+        }
+
+        // plans in log10, as they quickly overflow in a DAG, and
+        // children come before their parents, except for expressions that close a cycle
+        let cycles: HashSet<usize> = memo.cycles.iter().cloned().collect();
+        let mut plans = vec![f64::NEG_INFINITY; memo.groups.len()];
+        for (g, group) in memo.groups.iter().enumerate() {
+            plans[g] = log10_sum(group.exprs.iter()
+                .filter(|e| !cycles.contains(e))
+                .map(|e| memo.exprs[*e].children.iter().map(|c| plans[*c]).sum::<f64>()));
+        }
+
+        MemoStats {
+            groups: memo.groups.len(),
+            exprs: memo.exprs.len(),
+            roots: memo.entries.len(),
+            depth: memo.depth(),
+            group_sizes,
+            operators,
+            fan_out,
+            fan_in,
+            shared: parents.iter().filter(|p| p.len() > 1).count(),
+            reachable: visited.len(),
+            unreachable: memo.groups.len() - visited.len(),
+            plan_space_log10: log10_sum(memo.entries.iter().map(|e| plans[*e])),
+            phys: memo.phys.len(),
+            scalars: memo.scalars.len(),
+            cycles: memo.cycles.len(),
+            merges: memo.merges.len(),
+            commuted: memo.commuted.len(),
+        }
+    }
+
+    pub fn print(&self) {
+        fn dist(d: &BTreeMap<usize, usize>) -> String {
+            d.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>().join(", ")
+        }
+        println!("groups: {}", self.groups);
+        println!("expressions: {}", self.exprs);
+        println!("roots: {}", self.roots);
+        println!("depth: {}", self.depth);
+        println!("group sizes: {}", dist(&self.group_sizes));
+        println!("operators: {}", self.operators.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>().join(", "));
+        println!("fan-out: {}", dist(&self.fan_out));
+        println!("fan-in: {}", dist(&self.fan_in));
+        println!("shared groups: {}", self.shared);
+        println!("reachable groups: {} ({} unreachable)", self.reachable, self.unreachable);
+        println!("plan space: 10^{:.2}", self.plan_space_log10);
+        println!("physical expressions: {}", self.phys);
+        println!("scalars: {}", self.scalars);
+        println!("cycles: {}, merges: {}, commuted: {}", self.cycles, self.merges, self.commuted);
+    }
+}
+
+/// Log10 of a sum of values given as log10.
+fn log10_sum(values: impl Iterator<Item = f64>) -> f64 {
+    values.fold(f64::NEG_INFINITY, |a, b| {
+        let (hi, lo) = if a > b { (a, b) } else { (b, a) };
+        match hi {
+            f64::NEG_INFINITY => hi,
+            _ => hi + (1.0 + 10f64.powf(lo - hi)).log10(),
+        }
+    })
+}
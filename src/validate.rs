@@ -0,0 +1,123 @@
+use crate::generator::RawMemo;
+use std::collections::HashSet;
+use std::error::Error;
+
+impl RawMemo {
+    /// Check structural invariants of a generated memo, so that generator bugs are reported
+    /// here and not as wrong results of some backend.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let cycles: HashSet<usize> = self.cycles.iter().cloned().collect();
+
+        let mut owner: Vec<Option<usize>> = vec![None; self.exprs.len()];
+        for (g, group) in self.groups.iter().enumerate() {
+            if group.id != g {
+                return Err(format!("group {} has id {}", g, group.id).into());
+            }
+            if group.exprs.is_empty() {
+                return Err(format!("group {} is empty", g).into());
+            }
+            for j in group.exprs.iter() {
+                match owner.get(*j) {
+                    None => return Err(format!("group {} has unknown expression {}", g, j).into()),
+                    Some(Some(h)) => return Err(format!("expression {} is in groups {} and {}", j, h, g).into()),
+                    Some(None) => owner[*j] = Some(g),
+                }
+                for c in self.exprs[*j].children.iter() {
+                    if *c >= self.groups.len() {
+                        return Err(format!("expression {} has unknown child group {}", j, c).into());
+                    }
+                    // groups only reference earlier ones, which makes the memo acyclic
+                    if *c >= g && !cycles.contains(j) {
+                        return Err(format!("expression {} in group {} references later group {}", j, g, c).into());
+                    }
+                }
+            }
+        }
+        if let Some(j) = owner.iter().position(|g| g.is_none()) {
+            return Err(format!("expression {} is in no group", j).into());
+        }
+
+        if let Some(e) = self.entries.iter().find(|e| **e >= self.groups.len()) {
+            return Err(format!("unknown root group {}", e).into());
+        }
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut stack = self.entries.clone();
+        while let Some(g) = stack.pop() {
+            if visited.insert(g) {
+                for j in self.groups[g].exprs.iter() {
+                    stack.extend(self.exprs[*j].children.iter().filter(|c| !visited.contains(c)));
+                }
+            }
+        }
+        if let Some(g) = (0..self.groups.len()).find(|g| !visited.contains(g)) {
+            return Err(format!("group {} is not reachable from any root", g).into());
+        }
+
+        for (p, phys) in self.phys.iter().enumerate() {
+            if phys.expr.is_some_and(|j| j >= self.exprs.len()) || phys.children.iter().any(|c| c.0 >= self.groups.len()) {
+                return Err(format!("physical expression {} references unknown expressions or groups", p).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the groups of a shuffled (or deferred) memo, i.e., the chunks in insertion
+    /// order, are a valid way to insert the original memo.
+    pub fn validate_shuffled(&self, original: &RawMemo) -> Result<(), Box<dyn Error>> {
+        let n = original.groups.len();
+        let mut chunks: Vec<Vec<usize>> = vec![vec![]; n]; // of each original group
+        for (k, chunk) in self.groups.iter().enumerate() {
+            if chunk.id >= n {
+                return Err(format!("chunk {} has unknown group id {}", k, chunk.id).into());
+            }
+            for j in chunk.exprs.iter() {
+                if !original.groups[chunk.id].exprs.contains(j) {
+                    return Err(format!("chunk {} of group {} has expression {} of another group", k, chunk.id, j).into());
+                }
+                // children must have been inserted before
+                for c in original.exprs[*j].children.iter() {
+                    if chunks[*c].is_empty() {
+                        return Err(format!("chunk {} references group {} before inserting it", k, c).into());
+                    }
+                }
+            }
+            chunks[chunk.id].push(k);
+        }
+
+        for (g, ks) in chunks.iter().enumerate() {
+            let mut covered: HashSet<usize> = HashSet::new();
+            for k in ks.iter() {
+                covered.extend(self.groups[*k].exprs.iter());
+            }
+            if covered.len() != original.groups[g].exprs.len() {
+                return Err(format!("chunks of group {} cover {} of {} expressions", g, covered.len(), original.groups[g].exprs.len()).into());
+            }
+
+            if ks.is_empty() {
+                continue;
+            }
+
+            // chunks must share expressions, directly or not, to be found in the same group
+            let mut reached: HashSet<usize> = self.groups[ks[0]].exprs.iter().cloned().collect();
+            let mut pending: Vec<usize> = ks[1..].to_vec();
+            while !pending.is_empty() {
+                let before = pending.len();
+                pending.retain(|k| {
+                    let exprs = &self.groups[*k].exprs;
+                    if exprs.iter().any(|j| reached.contains(j)) {
+                        reached.extend(exprs.iter());
+                        false
+                    } else {
+                        true
+                    }
+                });
+                if pending.len() == before {
+                    return Err(format!("chunk {} of group {} shares no expression with the others", pending[0], g).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}